@@ -0,0 +1,154 @@
+use crate::dns::{DnsPacket, DnsQuestion, DnsRecord, QueryType, ResultCode};
+
+/// Fluent construction of queries and responses, created by
+/// `DnsPacket::query` and `DnsPacket::response_to`. Section counts in the
+/// header are kept in sync by `build`.
+#[derive(Clone, Debug)]
+pub struct PacketBuilder {
+    packet: DnsPacket,
+}
+
+impl DnsPacket {
+    /// start a query with a single IN class question
+    pub fn query(name: impl Into<String>, qtype: QueryType) -> PacketBuilder {
+        let mut packet = DnsPacket::new();
+        packet.questions.push(DnsQuestion {
+            name: name.into(),
+            qtype,
+            qclass: 1,
+        });
+        PacketBuilder { packet }
+    }
+
+    /// start a response to `request`, copying its ID, opcode, RD and CD
+    /// flags and question
+    pub fn response_to(request: &DnsPacket) -> PacketBuilder {
+        let mut packet = DnsPacket::new();
+        packet.header.id = request.header.id;
+        packet.header.opcode = request.header.opcode;
+        packet.header.rd = request.header.rd;
+        packet.header.cd = request.header.cd;
+        packet.header.qr = true;
+        packet.questions = request.questions.clone();
+        PacketBuilder { packet }
+    }
+}
+
+impl PacketBuilder {
+    pub fn id(mut self, id: u16) -> Self {
+        self.packet.header.id = id;
+        self
+    }
+
+    pub fn recursion_desired(mut self, rd: bool) -> Self {
+        self.packet.header.rd = rd;
+        self
+    }
+
+    pub fn recursion_available(mut self, ra: bool) -> Self {
+        self.packet.header.ra = ra;
+        self
+    }
+
+    pub fn authoritative(mut self, aa: bool) -> Self {
+        self.packet.header.aa = aa;
+        self
+    }
+
+    pub fn truncated(mut self, tc: bool) -> Self {
+        self.packet.header.tc = tc;
+        self
+    }
+
+    pub fn checking_disabled(mut self, cd: bool) -> Self {
+        self.packet.header.cd = cd;
+        self
+    }
+
+    pub fn rcode(mut self, rcode: ResultCode) -> Self {
+        self.packet.header.rcode = rcode;
+        self
+    }
+
+    pub fn question(mut self, question: DnsQuestion) -> Self {
+        self.packet.questions.push(question);
+        self
+    }
+
+    pub fn answer(mut self, record: DnsRecord) -> Self {
+        self.packet.answers.push(record);
+        self
+    }
+
+    pub fn answers(mut self, records: impl IntoIterator<Item = DnsRecord>) -> Self {
+        self.packet.answers.extend(records);
+        self
+    }
+
+    pub fn authority(mut self, record: DnsRecord) -> Self {
+        self.packet.authorities.push(record);
+        self
+    }
+
+    pub fn authorities(mut self, records: impl IntoIterator<Item = DnsRecord>) -> Self {
+        self.packet.authorities.extend(records);
+        self
+    }
+
+    pub fn additional(mut self, record: DnsRecord) -> Self {
+        self.packet.resources.push(record);
+        self
+    }
+
+    pub fn additionals(mut self, records: impl IntoIterator<Item = DnsRecord>) -> Self {
+        self.packet.resources.extend(records);
+        self
+    }
+
+    pub fn build(mut self) -> DnsPacket {
+        let header = &mut self.packet.header;
+        header.qd_count = self.packet.questions.len() as u16;
+        header.an_count = self.packet.answers.len() as u16;
+        header.ns_count = self.packet.authorities.len() as u16;
+        header.ar_count = self.packet.resources.len() as u16;
+        self.packet
+    }
+}
+
+impl From<PacketBuilder> for DnsPacket {
+    fn from(builder: PacketBuilder) -> DnsPacket {
+        builder.build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dns::{DnsPacket, DnsRecord, QueryType, ResultCode};
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn query_and_response() {
+        let query = DnsPacket::query("example.com", QueryType::A)
+            .recursion_desired(true)
+            .checking_disabled(true)
+            .id(4242)
+            .build();
+        assert_eq!(query.header.id, 4242);
+        assert_eq!(query.header.qd_count, 1);
+        assert!(query.header.rd && !query.header.qr);
+
+        let response = DnsPacket::response_to(&query)
+            .recursion_available(true)
+            .answer(DnsRecord::A {
+                domain: "example.com".into(),
+                addr: Ipv4Addr::new(192, 0, 2, 1),
+                ttl: 60,
+            })
+            .rcode(ResultCode::NOERROR)
+            .build();
+        assert_eq!(response.header.id, 4242);
+        assert!(response.header.qr && response.header.rd && response.header.cd);
+        assert_eq!(response.questions, query.questions);
+        assert_eq!((response.header.qd_count, response.header.an_count), (1, 1));
+    }
+}
@@ -0,0 +1,514 @@
+//! Answer cache shared by all resolutions.
+//!
+//! Records are cached as RRsets keyed by owner name, type and class, each
+//! with an absolute expiry. TTLs are clamped on the way in and count down
+//! on the way out. When the cache is full the least recently used sets
+//! are evicted, the size of each set is estimated from its owner name
+//! and the wire format of its records.
+//!
+//! Negative answers are cached as well (RFC 2308): NXDOMAIN for a whole
+//! name and NODATA for a name and type, each together with the SOA that
+//! came in the authority section.
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::dns::{DnsRecord, QueryType, ResultCode};
+use crate::rdata;
+use crate::resolver::Resolution;
+use crate::rrset::{self, RRset};
+
+/// Limits of a `Cache`.
+#[derive(Clone, Debug)]
+pub struct CacheConfig {
+    /// TTLs below this are raised to it
+    pub min_ttl: u32,
+    /// TTLs above this are lowered to it
+    pub max_ttl: u32,
+    /// upper bound for the TTL of cached NXDOMAIN and NODATA answers
+    pub max_negative_ttl: u32,
+    /// estimated bytes of record data kept at most, counting owner names
+    /// and the wire format of every record
+    pub max_bytes: usize,
+    /// how many RRsets and negative answers are kept at most, on top of
+    /// `max_bytes`
+    pub max_entry_count: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            min_ttl: 0,
+            max_ttl: 86400,
+            max_negative_ttl: 3600,
+            max_bytes: 4 * 1024 * 1024,
+            max_entry_count: 10000,
+        }
+    }
+}
+
+/// `rtype` is `None` for NXDOMAIN entries, which cover every type
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct Key {
+    name: String,
+    rtype: Option<QueryType>,
+    class: u16,
+}
+
+impl Key {
+    fn new(name: &str, rtype: Option<QueryType>, class: u16) -> Key {
+        Key {
+            name: name.to_ascii_lowercase(),
+            rtype,
+            class,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    /// the cached set, or the SOA of a negative answer
+    rrset: RRset,
+    /// response code of a negative answer
+    negative: Option<ResultCode>,
+    expires: Instant,
+    /// position in the LRU order
+    used: u64,
+    /// estimated size, see `size_of`
+    size: usize,
+}
+
+/// fixed part of a record on the wire: type, class, TTL and RDLENGTH
+const RECORD_OVERHEAD: usize = 10;
+
+/// estimated size of a cached set: its owner name once, and each record's
+/// data with the fixed fields that go along with it
+fn size_of(key: &Key, rrset: &RRset) -> usize {
+    key.name.len()
+        + rrset
+            .rdata
+            .iter()
+            .map(|rdata| RECORD_OVERHEAD + rdata::wire(rdata.as_ref()).len())
+            .sum::<usize>()
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    entries: HashMap<Key, Entry>,
+    /// keys by last use, oldest first
+    lru: BTreeMap<u64, Key>,
+    clock: u64,
+    /// sum of the sizes of all entries
+    bytes: usize,
+}
+
+impl Inner {
+    fn touch(&mut self, key: &Key) {
+        self.clock += 1;
+        let clock = self.clock;
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.lru.remove(&entry.used);
+            entry.used = clock;
+            self.lru.insert(clock, key.clone());
+        }
+    }
+
+    fn remove(&mut self, key: &Key) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.lru.remove(&entry.used);
+            self.bytes -= entry.size;
+        }
+    }
+}
+
+/// Thread safe, TTL respecting RRset cache.
+#[derive(Debug, Default)]
+pub struct Cache {
+    config: CacheConfig,
+    inner: Mutex<Inner>,
+}
+
+impl Cache {
+    pub fn new(config: CacheConfig) -> Cache {
+        Cache {
+            config,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    pub fn config(&self) -> &CacheConfig {
+        &self.config
+    }
+
+    /// store a set, replacing any set with the same name, type and class
+    pub fn insert(&self, rrset: RRset) {
+        self.insert_at(rrset, Instant::now());
+    }
+
+    /// group records into sets and store each of them
+    pub fn insert_records(&self, records: &[DnsRecord]) {
+        let now = Instant::now();
+        for set in rrset::group(records) {
+            self.insert_at(set, now);
+        }
+    }
+
+    fn insert_at(&self, mut rrset: RRset, now: Instant) {
+        rrset.ttl = rrset.ttl.clamp(self.config.min_ttl, self.config.max_ttl);
+        let key = Key::new(&rrset.name, Some(rrset.rtype), rrset.class);
+        self.store(key, rrset, None, now);
+    }
+
+    /// Cache a negative answer for a question. NXDOMAIN covers every type
+    /// at `qname`, NOERROR (NODATA) only `qtype`. The TTL is the lower of
+    /// the SOA's TTL and its minimum field; answers without a SOA in
+    /// `authorities` aren't cached.
+    pub fn insert_negative(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        rcode: ResultCode,
+        authorities: &[DnsRecord],
+    ) {
+        self.insert_negative_at(qname, qtype, rcode, authorities, Instant::now());
+    }
+
+    fn insert_negative_at(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        rcode: ResultCode,
+        authorities: &[DnsRecord],
+        now: Instant,
+    ) {
+        let rtype = match rcode {
+            ResultCode::NXDOMAIN => None,
+            ResultCode::NOERROR => Some(qtype),
+            _ => return,
+        };
+        let Some((soa, minimum)) = authorities.iter().find_map(|r| match r {
+            DnsRecord::SOA { minimum, .. } => Some((r, *minimum)),
+            _ => None,
+        }) else {
+            return;
+        };
+        let Some(mut soa) = RRset::from_record(soa) else {
+            return;
+        };
+
+        soa.ttl = soa.ttl.min(minimum).min(self.config.max_negative_ttl);
+        self.store(Key::new(qname, rtype, 1), soa, Some(rcode), now);
+    }
+
+    fn store(&self, key: Key, rrset: RRset, negative: Option<ResultCode>, now: Instant) {
+        if rrset.ttl == 0 || rrset.is_empty() || self.config.max_entry_count == 0 {
+            return;
+        }
+        let size = size_of(&key, &rrset);
+        if size > self.config.max_bytes {
+            return;
+        }
+
+        let expires = now + Duration::from_secs(rrset.ttl as u64);
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.remove(&key);
+        while inner.entries.len() >= self.config.max_entry_count
+            || inner.bytes + size > self.config.max_bytes
+        {
+            let Some((_, oldest)) = inner.lru.pop_first() else {
+                break;
+            };
+            if let Some(entry) = inner.entries.remove(&oldest) {
+                inner.bytes -= entry.size;
+            }
+        }
+        inner.bytes += size;
+        inner.entries.insert(
+            key.clone(),
+            Entry {
+                rrset,
+                negative,
+                expires,
+                used: 0,
+                size,
+            },
+        );
+        inner.touch(&key);
+    }
+
+    /// the cached set for `name` and `rtype` with its remaining TTL
+    pub fn get(&self, name: &str, rtype: QueryType) -> Option<RRset> {
+        self.get_at(name, rtype, Instant::now())
+    }
+
+    fn get_at(&self, name: &str, rtype: QueryType, now: Instant) -> Option<RRset> {
+        match self.entry_at(Key::new(name, Some(rtype), 1), now)? {
+            (rrset, None) => Some(rrset),
+            (_, Some(_)) => None,
+        }
+    }
+
+    /// a cached NXDOMAIN for `name`, or NODATA for `name` and `rtype`,
+    /// with the SOA to serve alongside
+    fn negative_at(
+        &self,
+        name: &str,
+        rtype: QueryType,
+        now: Instant,
+    ) -> Option<(ResultCode, RRset)> {
+        [None, Some(rtype)].into_iter().find_map(|rtype| {
+            match self.entry_at(Key::new(name, rtype, 1), now)? {
+                (soa, Some(rcode)) => Some((rcode, soa)),
+                (_, None) => None,
+            }
+        })
+    }
+
+    /// the data of an entry with its remaining TTL
+    fn entry_at(&self, key: Key, now: Instant) -> Option<(RRset, Option<ResultCode>)> {
+        let mut inner = self.inner.lock().unwrap();
+
+        let entry = inner.entries.get(&key)?;
+        if entry.expires <= now {
+            inner.remove(&key);
+            return None;
+        }
+
+        let mut rrset = entry.rrset.clone();
+        // rounded up, a set served right away keeps its full TTL
+        rrset.ttl = (entry.expires - now).as_millis().div_ceil(1000) as u32;
+        let negative = entry.negative;
+        inner.touch(&key);
+        Some((rrset, negative))
+    }
+
+    /// The cached answer to a question, following up to `max_chain` cached
+    /// CNAMEs. `None` unless the whole chain down to the requested type,
+    /// or to a cached negative answer, is cached.
+    pub fn lookup(&self, qname: &str, qtype: QueryType, max_chain: usize) -> Option<Resolution> {
+        let now = Instant::now();
+        let mut answers = Vec::new();
+        let mut name = qname.to_owned();
+
+        for _ in 0..=max_chain {
+            if let Some(set) = self.get_at(&name, qtype, now) {
+                answers.extend(set.records());
+                return Some(Resolution {
+                    rcode: ResultCode::NOERROR,
+                    answers,
+                    authorities: Vec::new(),
+                    additionals: Vec::new(),
+                });
+            }
+            if let Some((rcode, soa)) = self.negative_at(&name, qtype, now) {
+                return Some(Resolution {
+                    rcode,
+                    answers,
+                    authorities: soa.records(),
+                    additionals: Vec::new(),
+                });
+            }
+            if qtype == QueryType::CNAME {
+                return None;
+            }
+
+            let cname = self.get_at(&name, QueryType::CNAME, now)?;
+            let records = cname.records();
+            name = match records.first() {
+                Some(DnsRecord::CNAME { host, .. }) => host.clone(),
+                _ => return None,
+            };
+            answers.extend(records);
+        }
+        None
+    }
+
+    /// number of cached sets, including expired ones not yet removed
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().entries.len()
+    }
+
+    /// estimated size of the cached sets, as limited by `max_bytes`
+    pub fn bytes(&self) -> usize {
+        self.inner.lock().unwrap().bytes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&self) {
+        *self.inner.lock().unwrap() = Inner::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Cache, CacheConfig};
+    use crate::dns::{DnsRecord, QueryType, ResultCode};
+    use crate::rrset::RRset;
+    use std::time::{Duration, Instant};
+
+    fn a(name: &str, ttl: u32) -> RRset {
+        RRset::from_record(&DnsRecord::A {
+            domain: name.into(),
+            addr: "192.0.2.1".parse().unwrap(),
+            ttl,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn ttl_counts_down_and_is_clamped() {
+        let cache = Cache::new(CacheConfig {
+            min_ttl: 60,
+            max_ttl: 600,
+            ..CacheConfig::default()
+        });
+        let now = Instant::now();
+        cache.insert_at(a("short.example.com", 5), now);
+        cache.insert_at(a("long.example.com", 86400), now);
+
+        let later = now + Duration::from_secs(30);
+        let get = |name| cache.get_at(name, QueryType::A, later).map(|s| s.ttl);
+        assert_eq!(get("short.example.com"), Some(30));
+        assert_eq!(get("LONG.example.com"), Some(570));
+
+        let expired = now + Duration::from_secs(60);
+        assert!(cache
+            .get_at("short.example.com", QueryType::A, expired)
+            .is_none());
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let cache = Cache::new(CacheConfig {
+            max_entry_count: 2,
+            ..CacheConfig::default()
+        });
+        cache.insert(a("one.example.com", 300));
+        cache.insert(a("two.example.com", 300));
+        assert!(cache.get("one.example.com", QueryType::A).is_some());
+
+        cache.insert(a("three.example.com", 300));
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get("two.example.com", QueryType::A).is_none());
+        assert!(cache.get("one.example.com", QueryType::A).is_some());
+        assert!(cache.get("three.example.com", QueryType::A).is_some());
+    }
+
+    #[test]
+    fn evicts_to_stay_within_max_bytes() {
+        // an A set for a 15 byte name is 15 + 10 + 4 bytes
+        let cache = Cache::new(CacheConfig {
+            max_bytes: 60,
+            ..CacheConfig::default()
+        });
+        cache.insert(a("one.example.com", 300));
+        cache.insert(a("two.example.com", 300));
+        assert_eq!(cache.bytes(), 58);
+        assert!(cache.get("one.example.com", QueryType::A).is_some());
+
+        cache.insert(a("six.example.com", 300));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.bytes(), 58);
+        assert!(cache.get("two.example.com", QueryType::A).is_none());
+
+        // replacing a set doesn't count it twice
+        cache.insert(a("six.example.com", 600));
+        assert_eq!(cache.bytes(), 58);
+
+        // a set larger than the whole cache isn't stored
+        let mut big = a("big.example.com", 300);
+        big.rdata = (0..4).map(|_| big.rdata[0].clone()).collect();
+        cache.insert(big);
+        assert!(cache.get("big.example.com", QueryType::A).is_none());
+        assert_eq!(cache.len(), 2);
+
+        cache.clear();
+        assert_eq!(cache.bytes(), 0);
+    }
+
+    #[test]
+    fn lookup_follows_cname() {
+        let cache = Cache::default();
+        cache.insert_records(&[
+            DnsRecord::CNAME {
+                domain: "alias.example.com".into(),
+                host: "www.example.com".into(),
+                ttl: 300,
+            },
+            DnsRecord::A {
+                domain: "www.example.com".into(),
+                addr: "192.0.2.80".parse().unwrap(),
+                ttl: 300,
+            },
+        ]);
+
+        let answers = cache
+            .lookup("alias.example.com", QueryType::A, 8)
+            .unwrap()
+            .answers;
+        assert_eq!(answers.len(), 2);
+        assert_eq!(answers[1].domain(), "www.example.com");
+        assert!(cache
+            .lookup("alias.example.com", QueryType::MX, 8)
+            .is_none());
+        // no further than the resolver would follow
+        assert!(cache.lookup("alias.example.com", QueryType::A, 0).is_none());
+    }
+
+    #[test]
+    fn negative_answers() {
+        let cache = Cache::default();
+        let soa = DnsRecord::SOA {
+            domain: "example.com".into(),
+            m_name: "ns1.example.com".into(),
+            r_name: "hostmaster.example.com".into(),
+            serial: 1,
+            refresh: 7200,
+            retry: 3600,
+            expire: 1209600,
+            minimum: 300,
+            ttl: 3600,
+        };
+        let now = Instant::now();
+        cache.insert_negative_at(
+            "nope.example.com",
+            QueryType::A,
+            ResultCode::NXDOMAIN,
+            std::slice::from_ref(&soa),
+            now,
+        );
+        cache.insert_negative_at(
+            "www.example.com",
+            QueryType::MX,
+            ResultCode::NOERROR,
+            std::slice::from_ref(&soa),
+            now,
+        );
+        // no SOA, nothing to cache
+        cache.insert_negative("other.example.com", QueryType::A, ResultCode::NXDOMAIN, &[]);
+
+        let nxdomain = cache
+            .lookup("nope.example.com", QueryType::AAAA, 8)
+            .unwrap();
+        assert_eq!(nxdomain.rcode, ResultCode::NXDOMAIN);
+        assert!(nxdomain.answers.is_empty());
+        // the SOA minimum caps the TTL
+        assert_eq!(nxdomain.authorities[0].ttl(), 300);
+
+        let nodata = cache.lookup("www.example.com", QueryType::MX, 8).unwrap();
+        assert_eq!(nodata.rcode, ResultCode::NOERROR);
+        assert!(matches!(nodata.authorities[0], DnsRecord::SOA { .. }));
+
+        assert!(cache.lookup("www.example.com", QueryType::A, 8).is_none());
+        assert!(cache.lookup("other.example.com", QueryType::A, 8).is_none());
+        let later = now + Duration::from_secs(300);
+        assert!(cache
+            .negative_at("nope.example.com", QueryType::A, later)
+            .is_none());
+    }
+}
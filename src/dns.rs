@@ -1,13 +1,15 @@
 use std::{
-    io::{Read, Seek, Write},
+    io::{Read, Seek, SeekFrom, Write},
     net::{Ipv4Addr, Ipv6Addr},
 };
 type Error = Box<dyn std::error::Error>;
 type Result<T> = std::result::Result<T, Error>;
 
 use crate::packet;
+use crate::rdata::{self, builtin_types, Rdata};
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ResultCode {
     NOERROR = 0,
     FORMERR = 1,
@@ -44,6 +46,7 @@ impl ResultCode {
 // |                    ARCOUNT                    |
 // +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DnsHeader {
     pub id: u16, // packet identifier 16 bits
 
@@ -64,6 +67,12 @@ pub struct DnsHeader {
     pub ns_count: u16, // authority count 16 bits
     pub ar_count: u16, // additional count 16 bits
 }
+impl Default for DnsHeader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl DnsHeader {
     pub fn new() -> DnsHeader {
         DnsHeader {
@@ -97,7 +106,7 @@ impl DnsHeader {
         self.opcode = a >> 3;
         self.qr = (a & (1 << 7)) > 0;
 
-        self.rcode = ResultCode::from_num(b);
+        self.rcode = ResultCode::from_num(b & 0x0F);
         self.cd = (b & (1 << 4)) > 0;
         self.ad = (b & (1 << 5)) > 0;
         self.z = (b & (1 << 6)) > 0;
@@ -138,6 +147,7 @@ impl DnsHeader {
     }
 }
 #[derive(PartialEq, Eq, Debug, Clone, Hash, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum QueryType {
     UNKNOWN(u16),
     A,
@@ -145,7 +155,9 @@ pub enum QueryType {
     CNAME,
     SOA,
     MX,
+    TXT,
     AAAA,
+    OPT,
 }
 
 impl QueryType {
@@ -157,7 +169,9 @@ impl QueryType {
             QueryType::CNAME => 5,
             QueryType::SOA => 6,
             QueryType::MX => 15,
+            QueryType::TXT => 16,
             QueryType::AAAA => 28,
+            QueryType::OPT => 41,
         }
     }
 
@@ -168,7 +182,9 @@ impl QueryType {
             5 => QueryType::CNAME,
             6 => QueryType::SOA,
             15 => QueryType::MX,
+            16 => QueryType::TXT,
             28 => QueryType::AAAA,
+            41 => QueryType::OPT,
             _ => QueryType::UNKNOWN(num),
         }
     }
@@ -182,12 +198,19 @@ impl QueryType {
 // |                    QCLASS                     |
 // +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DnsQuestion {
     pub name: String,
     pub qtype: QueryType,
     pub qclass: u16,
 }
 
+impl Default for DnsQuestion {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl DnsQuestion {
     pub fn new() -> DnsQuestion {
         DnsQuestion {
@@ -198,7 +221,7 @@ impl DnsQuestion {
     }
 
     pub fn read<R: Read + Seek>(&mut self, buffer: &mut packet::PacketReader<R>) -> Result<()> {
-        self.name = buffer.read_name()?;
+        self.name = buffer.read_name_preserving_case()?;
         self.qtype = QueryType::from_num(buffer.read_u16()?);
         self.qclass = buffer.read_u16()?;
 
@@ -214,6 +237,104 @@ impl DnsQuestion {
     }
 }
 
+/// `DnsRecord` with a variant for each built-in type, listed by
+/// `rdata::builtin_types`, and the accessors that go through all of them.
+/// Variants carry the owner and TTL around the fields of the type's
+/// struct in `rdata`.
+macro_rules! dns_record {
+    ($($name:ident { $($(#[$doc:meta])* $field:ident: $ty:ty),* $(,)? }),* $(,)?) => {
+        #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+        #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+        pub enum DnsRecord {
+            UNKNOWN {
+                domain: String,
+                qtype: u16,
+                /// the RDATA as received, written back out unchanged
+                data: Vec<u8>,
+                ttl: u32,
+            },
+            $($name {
+                domain: String,
+                $($(#[$doc])* $field: $ty,)*
+                ttl: u32,
+            },)*
+            /// EDNS pseudo record (RFC 6891), only found in the additional section
+            OPT {
+                /// largest UDP payload the sender can receive, sent as CLASS
+                packet_len: u16,
+                /// extended RCODE, version and flags, sent as TTL
+                flags: u32,
+                /// options as (code, data) pairs
+                options: Vec<(u16, Vec<u8>)>,
+            },
+            /// a type registered with `rdata::register`
+            CUSTOM {
+                domain: String,
+                #[cfg_attr(feature = "serde", serde(with = "crate::rdata::serde_rdata"))]
+                rdata: Box<dyn Rdata>,
+                ttl: u32,
+            },
+        }
+
+        impl DnsRecord {
+            /// owner name of the record
+            pub fn domain(&self) -> &str {
+                match self {
+                    DnsRecord::UNKNOWN { domain, .. } | DnsRecord::CUSTOM { domain, .. } => domain,
+                    $(DnsRecord::$name { domain, .. } => domain,)*
+                    DnsRecord::OPT { .. } => "",
+                }
+            }
+
+            /// time to live of the record
+            pub fn ttl(&self) -> u32 {
+                match *self {
+                    DnsRecord::UNKNOWN { ttl, .. } | DnsRecord::CUSTOM { ttl, .. } => ttl,
+                    $(DnsRecord::$name { ttl, .. } => ttl,)*
+                    DnsRecord::OPT { .. } => 0,
+                }
+            }
+
+            /// type of the record
+            pub fn qtype(&self) -> QueryType {
+                match *self {
+                    DnsRecord::UNKNOWN { qtype, .. } => QueryType::from_num(qtype),
+                    $(DnsRecord::$name { .. } => QueryType::$name,)*
+                    DnsRecord::OPT { .. } => QueryType::OPT,
+                    DnsRecord::CUSTOM { ref rdata, .. } => QueryType::from_num(rdata.rtype()),
+                }
+            }
+
+            /// record data as a trait object, `None` for unknown types whose data
+            /// was skipped while reading and for OPT
+            pub fn rdata(&self) -> Option<Box<dyn Rdata>> {
+                let rdata: Box<dyn Rdata> = match self {
+                    DnsRecord::UNKNOWN { .. } | DnsRecord::OPT { .. } => return None,
+                    $(DnsRecord::$name { $($field,)* .. } => Box::new(rdata::$name {
+                        $($field: Clone::clone($field),)*
+                    }),)*
+                    DnsRecord::CUSTOM { rdata, .. } => rdata.clone(),
+                };
+                Some(rdata)
+            }
+
+            /// build a record from its owner, TTL and data. Built-in types become
+            /// their own variant, anything else `CUSTOM`
+            pub fn from_rdata(domain: String, ttl: u32, rdata: Box<dyn Rdata>) -> DnsRecord {
+                let any = (*rdata).as_any();
+                $(if let Some(r) = any.downcast_ref::<rdata::$name>() {
+                    return DnsRecord::$name {
+                        domain,
+                        $($field: Clone::clone(&r.$field),)*
+                        ttl,
+                    };
+                })*
+                DnsRecord::CUSTOM { domain, rdata, ttl }
+            }
+        }
+    };
+}
+
 // +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
 // |                    NAME                       |
 // +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
@@ -227,276 +348,125 @@ impl DnsQuestion {
 // +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
 // |                    RDATA                      |
 // +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
-#[derive(Debug, Clone)]
-pub enum DnsRecord {
-    UNKNOWN {
-        domain: String,
-        qtype: u16,
-        data_len: u16,
-        ttl: u32,
-    },
-    A {
-        domain: String,
-        addr: Ipv4Addr,
-        ttl: u32,
-    },
-    AAAA {
-        domain: String,
-        addr: Ipv6Addr,
-        ttl: u32,
-    },
-    NS {
-        domain: String,
-        host: String,
-        ttl: u32,
-    },
-    CNAME {
-        domain: String,
-        host: String,
-        ttl: u32,
-    },
-    MX {
-        domain: String,
-        priority: u16,
-        host: String,
-        ttl: u32,
-    },
-    SOA {
-        domain: String,
-        m_name: String,
-        r_name: String,
-        serial: u32,
-        refresh: u32,
-        retry: u32,
-        expire: u32,
-        minimum: u32,
-        ttl: u32,
-    },
-}
+builtin_types!(dns_record);
 
 impl DnsRecord {
     pub fn read<R: Read + Seek>(buffer: &mut packet::PacketReader<R>) -> Result<Self> {
         let domain = buffer.read_name()?;
 
         let qtype_num = buffer.read_u16()?;
-        let qtype = QueryType::from_num(qtype_num);
-        let _ = buffer.read_u16()?;
+        let class = buffer.read_u16()?;
         let ttl = buffer.read_u32()?;
         let data_len = buffer.read_u16()?;
 
-        match qtype {
-            QueryType::A => {
-                let raw_addr = buffer.read_u32()?;
-                let addr = Ipv4Addr::new(
-                    (raw_addr >> 24) as u8,
-                    (raw_addr >> 16) as u8,
-                    (raw_addr >> 8) as u8,
-                    (raw_addr) as u8,
-                );
-
-                Ok(DnsRecord::A { domain, addr, ttl })
-            }
-            QueryType::AAAA => {
-                let raw_addr1 = buffer.read_u32()?;
-                let raw_addr2 = buffer.read_u32()?;
-                let raw_addr3 = buffer.read_u32()?;
-                let raw_addr4 = buffer.read_u32()?;
-                let addr = Ipv6Addr::new(
-                    ((raw_addr1 >> 16) & 0xFFFF) as u16,
-                    ((raw_addr1) & 0xFFFF) as u16,
-                    ((raw_addr2 >> 16) & 0xFFFF) as u16,
-                    ((raw_addr2) & 0xFFFF) as u16,
-                    ((raw_addr3 >> 16) & 0xFFFF) as u16,
-                    ((raw_addr3) & 0xFFFF) as u16,
-                    ((raw_addr4 >> 16) & 0xFFFF) as u16,
-                    ((raw_addr4) & 0xFFFF) as u16,
-                );
-
-                Ok(DnsRecord::AAAA { domain, addr, ttl })
-            }
-            QueryType::NS => {
-                let host = buffer.read_name()?;
-
-                Ok(DnsRecord::NS { domain, host, ttl })
-            }
-            QueryType::CNAME => {
-                let cname = buffer.read_name()?;
-
-                Ok(DnsRecord::CNAME {
-                    domain,
-                    host: cname,
-                    ttl,
-                })
+        if qtype_num == QueryType::OPT.to_num() {
+            let mut options = Vec::new();
+            let mut left = data_len as usize;
+            while left >= 4 {
+                let code = buffer.read_u16()?;
+                let len = buffer.read_u16()? as usize;
+                if len > left - 4 {
+                    return Err("EDNS option longer than the OPT record".into());
+                }
+                let mut data = vec![0; len];
+                buffer.read.read_exact(&mut data)?;
+                options.push((code, data));
+                left -= 4 + len;
             }
-            QueryType::SOA => {
-                let m_name = buffer.read_name()?;
-                let r_name = buffer.read_name()?;
+            buffer.step(left)?;
+            return Ok(DnsRecord::OPT {
+                packet_len: class,
+                flags: ttl,
+                options,
+            });
+        }
 
-                let serial = buffer.read_u32()?;
-                let refresh = buffer.read_u32()?;
-                let retry = buffer.read_u32()?;
-                let expire = buffer.read_u32()?;
-                let minimum = buffer.read_u32()?;
+        let start = buffer.read.stream_position()?;
+        let mut reader = packet::PacketReader::new(&mut buffer.read as &mut dyn packet::ReadSeek);
+        let decoded = rdata::decode(qtype_num, &mut reader, data_len)?;
 
-                Ok(DnsRecord::SOA {
-                    domain,
-                    m_name,
-                    r_name,
-                    serial,
-                    refresh,
-                    retry,
-                    expire,
-                    minimum,
-                    ttl,
-                })
-            }
-            QueryType::MX => {
-                let priority = buffer.read_u16()?;
-                let host = buffer.read_name()?;
-
-                Ok(DnsRecord::MX {
-                    domain,
-                    priority,
-                    host,
-                    ttl,
-                })
-            }
-            _ => {
-                buffer.step(data_len as usize)?;
+        // whatever the decoder consumed, continue right after the RDATA
+        buffer.read.seek(SeekFrom::Start(start + data_len as u64))?;
 
+        match decoded {
+            Some(rdata) => Ok(DnsRecord::from_rdata(domain, ttl, rdata)),
+            None => {
+                buffer.read.seek(SeekFrom::Start(start))?;
+                let mut data = vec![0; data_len as usize];
+                buffer.read.read_exact(&mut data)?;
                 Ok(DnsRecord::UNKNOWN {
                     domain,
                     qtype: qtype_num,
-                    data_len,
+                    data,
                     ttl,
                 })
             }
         }
     }
+
     pub fn write<R: Write>(&self, buffer: &mut packet::PacketWriter<R>) -> Result<usize> {
-        let mut size = 0;
-        match *self {
-            DnsRecord::A {
-                ref domain,
-                ref addr,
-                ttl,
-            } => {
-                size += buffer.write_name(domain)?;
-                buffer.write_u16(QueryType::A.to_num())?;
-                buffer.write_u16(1)?;
-                buffer.write_u32(ttl)?;
-
-                buffer.write_u16(4)?;
-
-                let octets = addr.octets();
-                buffer.write_u8(octets[0])?;
-                buffer.write_u8(octets[1])?;
-                buffer.write_u8(octets[2])?;
-                buffer.write_u8(octets[3])?;
-                size += 14;
-            }
-            DnsRecord::AAAA {
-                ref domain,
-                ref addr,
-                ttl,
-            } => {
-                size += buffer.write_name(domain)?;
-                buffer.write_u16(QueryType::AAAA.to_num())?;
-                buffer.write_u16(1)?;
-                buffer.write_u32(ttl)?;
-
-                buffer.write_u16(16)?;
-                size += 10;
-
-                for octet in &addr.segments() {
-                    buffer.write_u16(*octet)?;
-                    size += 2;
-                }
-            }
-            DnsRecord::NS {
-                ref domain,
-                ref host,
-                ttl,
-            } => {
-                size += buffer.write_name(domain)?;
-                buffer.write_u16(QueryType::NS.to_num())?;
-                buffer.write_u16(1)?;
-                buffer.write_u32(ttl)?;
-                size += 8;
-
-                buffer.write_u16(buffer.get_name_len(host) as u16)?;
-                size += 2;
-                size += buffer.write_name(host)?;
-            }
-            DnsRecord::CNAME {
-                ref domain,
-                ref host,
-                ttl,
-            } => {
-                size += buffer.write_name(domain)?;
-                buffer.write_u16(QueryType::CNAME.to_num())?;
-                buffer.write_u16(1)?;
-                buffer.write_u32(ttl)?;
-                size += 8;
-
-                buffer.write_u16(buffer.get_name_len(host) as u16)?;
-                size += 2;
-                size += buffer.write_name(host)?;
+        if let DnsRecord::OPT {
+            packet_len,
+            flags,
+            options,
+        } = self
+        {
+            let data_len: usize = options.iter().map(|(_, data)| 4 + data.len()).sum();
+            if data_len > u16::MAX as usize {
+                return Err("EDNS options too long".into());
             }
-            DnsRecord::SOA {
-                ref domain,
-                ref m_name,
-                ref r_name,
-                serial,
-                refresh,
-                retry,
-                expire,
-                minimum,
-                ttl,
-            } => {
-                size += buffer.write_name(domain)?;
-                buffer.write_u16(QueryType::SOA.to_num())?;
-                buffer.write_u16(1)?;
-                buffer.write_u32(ttl)?;
-                size += 8;
-
-                size += buffer.write_name(m_name)?;
-                size += buffer.write_name(r_name)?;
-
-                buffer.write_u32(serial)?;
-                buffer.write_u32(refresh)?;
-                buffer.write_u32(retry)?;
-                buffer.write_u32(expire)?;
-                buffer.write_u32(minimum)?;
-                size += 20;
-            }
-            DnsRecord::MX {
-                ref domain,
-                priority,
-                ref host,
-                ttl,
-            } => {
-                size += buffer.write_name(domain)?;
-                buffer.write_u16(QueryType::MX.to_num())?;
-                buffer.write_u16(1)?;
-                buffer.write_u32(ttl)?;
-                size += 8;
-
-                buffer.write_u16(buffer.get_name_len(host) as u16 + 2)?;
-                size += 4;
-
-                buffer.write_u16(priority)?;
-                size += 2;
-                size += buffer.write_name(host)?;
+
+            let mut size = buffer.write_name("")?;
+            buffer.write_u16(QueryType::OPT.to_num())?;
+            buffer.write_u16(*packet_len)?;
+            buffer.write_u32(*flags)?;
+            buffer.write_u16(data_len as u16)?;
+            for (code, data) in options {
+                buffer.write_u16(*code)?;
+                buffer.write_u16(data.len() as u16)?;
+                buffer.write.write_all(data)?;
             }
-            _ => {
-                println!("unknown record: {:?}", self);
+            size += 10 + data_len;
+            return Ok(size);
+        }
+
+        let (rtype, data) = match (self, self.rdata()) {
+            (DnsRecord::UNKNOWN { qtype, data, .. }, _) => (*qtype, data.clone()),
+            (_, Some(rdata)) => {
+                let mut data = Vec::new();
+                rdata.encode(&mut data)?;
+                (rdata.rtype(), data)
             }
+            (_, None) => return Err(format!("no RDATA to write for {:?}", self).into()),
+        };
+        if data.len() > u16::MAX as usize {
+            return Err("RDATA too long".into());
         }
+
+        let mut size = buffer.write_name(self.domain())?;
+        buffer.write_u16(rtype)?;
+        buffer.write_u16(1)?;
+        buffer.write_u32(self.ttl())?;
+        buffer.write_u16(data.len() as u16)?;
+        buffer.write.write_all(&data)?;
+        size += 10 + data.len();
+
         Ok(size)
     }
 }
+/// whether `name` is `zone` itself or a name below it, the root being the
+/// empty string
+pub fn is_subdomain(name: &str, zone: &str) -> bool {
+    if zone.is_empty() || name.eq_ignore_ascii_case(zone) {
+        return true;
+    }
+    name.len() > zone.len()
+        && name.as_bytes()[name.len() - zone.len() - 1] == b'.'
+        && name.as_bytes()[name.len() - zone.len()..].eq_ignore_ascii_case(zone.as_bytes())
+}
+
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DnsPacket {
     pub header: DnsHeader,
     pub questions: Vec<DnsQuestion>,
@@ -505,6 +475,12 @@ pub struct DnsPacket {
     pub resources: Vec<DnsRecord>,
 }
 
+impl Default for DnsPacket {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl DnsPacket {
     pub fn new() -> DnsPacket {
         DnsPacket {
@@ -566,61 +542,67 @@ impl DnsPacket {
         Ok(size)
     }
 
-    /// whether there is A record
-    pub fn have_a(&self) -> bool {
-        self.answers
+    /// the EDNS OPT record of the packet, if it has one
+    pub fn edns(&self) -> Option<&DnsRecord> {
+        self.resources
             .iter()
-            .any(|record| matches!(record, DnsRecord::A { .. }))
+            .find(|record| matches!(record, DnsRecord::OPT { .. }))
     }
 
-    /// get first A record from a packet
-    pub fn get_first_a(&self) -> Option<Ipv4Addr> {
-        self.answers.iter().find_map(|record| match record {
-            DnsRecord::A { addr, .. } => Some(*addr),
+    /// data of the EDNS option `code`, if the packet carries it
+    pub fn edns_option(&self, code: u16) -> Option<&[u8]> {
+        match self.edns()? {
+            DnsRecord::OPT { options, .. } => options
+                .iter()
+                .find(|(c, _)| *c == code)
+                .map(|(_, data)| data.as_slice()),
             _ => None,
-        })
+        }
     }
 
-    /// get first CNAME record from a packet
-    pub fn get_first_cname(&self) -> Option<String> {
-        self.answers.iter().find_map(|record| match record {
-            DnsRecord::CNAME { host, .. } => Some(host.to_owned()),
-            _ => None,
-        })
+    /// whether the answer section holds records of `qtype` owned by
+    /// `qname`, i.e. answers the question
+    pub fn has_answer(&self, qname: &str, qtype: QueryType) -> bool {
+        self.answers
+            .iter()
+            .any(|record| record.qtype() == qtype && record.domain().eq_ignore_ascii_case(qname))
+    }
+
+    /// NODATA: the name exists but has no records of the asked type, told
+    /// by an empty NOERROR answer that is authoritative or carries the
+    /// zone's SOA (RFC 2308 section 2.2)
+    pub fn is_nodata(&self) -> bool {
+        self.header.rcode == ResultCode::NOERROR
+            && self.answers.is_empty()
+            && (self.header.aa
+                || self
+                    .authorities
+                    .iter()
+                    .any(|record| matches!(record, DnsRecord::SOA { .. })))
+    }
+
+    /// Drop every record owned by a name outside `zone`, the zone the
+    /// responding server was asked about. A server may only speak for its
+    /// own zone, anything else could be an attempt at cache poisoning.
+    pub fn retain_in_bailiwick(&mut self, zone: &str) {
+        let in_zone = |record: &DnsRecord| {
+            matches!(record, DnsRecord::OPT { .. }) || is_subdomain(record.domain(), zone)
+        };
+        self.answers.retain(in_zone);
+        self.authorities.retain(in_zone);
+        self.resources.retain(in_zone);
     }
 
     /// returns an iterator over all name servers in the authorities section,
     /// represented as (domain, host) tuples
-    fn get_ns<'a>(&'a self, qname: &'a str) -> impl Iterator<Item = (&'a str, &'a str)> {
+    pub fn get_ns<'a>(&'a self, qname: &'a str) -> impl Iterator<Item = (&'a str, &'a str)> {
         self.authorities.iter().filter_map(|record| {
             if let DnsRecord::NS { domain, host, .. } = record {
-                if qname.ends_with(domain) {
+                if is_subdomain(qname, domain) {
                     return Some((domain.as_str(), host.as_str()));
                 }
             }
             None
         })
     }
-
-    /// assume that name servers often bundle the corresponding A records
-    /// get it from resources section
-    pub fn get_resolved_ns(&self, qname: &str) -> Option<Ipv4Addr> {
-        self.get_ns(qname)
-            .flat_map(|(_, host)| {
-                self.resources
-                    .iter()
-                    .filter_map(move |record| match record {
-                        DnsRecord::A { domain, addr, .. } if domain == host => Some(addr),
-                        _ => None,
-                    })
-            })
-            .cloned()
-            .next()
-    }
-
-    /// get the host name of an appropriate name server. because in other cases
-    /// there won't be any A records in the additional section
-    pub fn get_unresolved_ns<'a>(&'a self, qname: &'a str) -> Option<&'a str> {
-        self.get_ns(qname).map(|(_, host)| host).next()
-    }
 }
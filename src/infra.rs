@@ -0,0 +1,389 @@
+//! What the resolver knows about the name servers it talks to.
+//!
+//! For every server address it keeps a smoothed round trip time and
+//! failure counts, like the infrastructure caches of Unbound and BIND.
+//! Faster servers are asked first, while now and then another one is
+//! tried so that changed conditions are noticed. Measurements fade with
+//! age and are forgotten after a while. Servers that keep failing are
+//! marked down, so that other resolutions don't wait for them again.
+//! Servers that repeatedly don't echo the letter case of questions are
+//! remembered for a while, so that queries to them aren't case
+//! randomized.
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rand::{thread_rng, Rng};
+
+/// assumed round trip time of servers that weren't measured yet, low
+/// enough to get them tried before known slow ones
+const UNKNOWN_RTT: Duration = Duration::from_millis(300);
+
+/// weight of a new sample in the smoothed round trip time
+const RTT_WEIGHT: f64 = 0.3;
+
+/// case randomized questions in a row a server has to echo in a single
+/// case before it's taken to not preserve case, so that one spoofed
+/// response can't turn randomization off
+const CASE_FOLD_CONFIRMATIONS: u32 = 2;
+
+/// Settings of an `InfraCache`.
+#[derive(Clone, Debug)]
+pub struct InfraConfig {
+    /// how long a server that didn't answer any try is skipped
+    pub down_time: Duration,
+    /// chance of asking a randomly picked server first instead of the
+    /// fastest one
+    pub exploration: f64,
+    /// the age at which a measured round trip time counts half
+    pub half_life: Duration,
+    /// how long a server's entry is kept after it was last queried
+    pub ttl: Duration,
+    /// how long a server that doesn't preserve the letter case of
+    /// questions is asked without case randomization
+    pub ignores_case_time: Duration,
+}
+
+impl Default for InfraConfig {
+    fn default() -> Self {
+        InfraConfig {
+            down_time: Duration::from_secs(60),
+            exploration: 0.05,
+            half_life: Duration::from_secs(300),
+            ttl: Duration::from_secs(900),
+            ignores_case_time: Duration::from_secs(3600),
+        }
+    }
+}
+
+/// Statistics of a single name server address.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServerInfo {
+    /// smoothed round trip time, timeouts count as the time waited
+    pub srtt: Duration,
+    /// queries sent
+    pub queries: u64,
+    /// queries that failed or timed out
+    pub errors: u64,
+    /// failed queries since the last response
+    pub failures: u32,
+    /// skipped until then, unless there is nothing else to ask
+    pub down_until: Option<Instant>,
+    /// when the server was last queried
+    pub updated: Instant,
+    /// case randomized questions in a row echoed in a single case
+    pub case_folds: u32,
+    /// asked without case randomization until then
+    pub ignores_case_until: Option<Instant>,
+}
+
+impl ServerInfo {
+    fn new(now: Instant) -> ServerInfo {
+        ServerInfo {
+            srtt: UNKNOWN_RTT,
+            queries: 0,
+            errors: 0,
+            failures: 0,
+            down_until: None,
+            updated: now,
+            case_folds: 0,
+            ignores_case_until: None,
+        }
+    }
+
+    /// the round trip time used for selection: the measurement fades
+    /// towards zero as it ages, so slow servers get another chance
+    fn effective_rtt(&self, half_life: Duration, now: Instant) -> Duration {
+        let age = now.saturating_duration_since(self.updated).as_secs_f64();
+        let half_life = half_life.as_secs_f64().max(f64::MIN_POSITIVE);
+        self.srtt.mul_f64(0.5f64.powf(age / half_life))
+    }
+
+    /// whether the entry is old enough to be forgotten
+    fn expired(&self, ttl: Duration, now: Instant) -> bool {
+        now.saturating_duration_since(self.updated) >= ttl
+            && self.down_until.is_none_or(|until| until <= now)
+    }
+
+    fn sample(&mut self, rtt: Duration, now: Instant) {
+        self.srtt = if self.queries == 0 {
+            rtt
+        } else {
+            self.srtt.mul_f64(1.0 - RTT_WEIGHT) + rtt.mul_f64(RTT_WEIGHT)
+        };
+        self.queries += 1;
+        self.updated = now;
+    }
+}
+
+/// Per address server information, shared by all resolutions.
+#[derive(Debug)]
+pub struct InfraCache {
+    config: InfraConfig,
+    servers: Mutex<HashMap<IpAddr, ServerInfo>>,
+    /// when expired entries are swept out next, until then they are only
+    /// skipped when looked up
+    next_sweep: Mutex<Instant>,
+}
+
+impl Default for InfraCache {
+    fn default() -> Self {
+        InfraCache::new(InfraConfig::default())
+    }
+}
+
+impl InfraCache {
+    pub fn new(config: InfraConfig) -> InfraCache {
+        InfraCache {
+            next_sweep: Mutex::new(Instant::now() + config.ttl),
+            config,
+            servers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn config(&self) -> &InfraConfig {
+        &self.config
+    }
+
+    pub fn get(&self, addr: IpAddr) -> Option<ServerInfo> {
+        let now = Instant::now();
+        let mut servers = self.servers.lock().unwrap();
+        match servers.get(&addr) {
+            Some(info) if info.expired(self.config.ttl, now) => {
+                servers.remove(&addr);
+                None
+            }
+            info => info.cloned(),
+        }
+    }
+
+    /// every known server with its statistics, fastest first
+    pub fn snapshot(&self) -> Vec<(IpAddr, ServerInfo)> {
+        let now = Instant::now();
+        let servers = self.servers.lock().unwrap();
+        let mut list: Vec<(IpAddr, ServerInfo)> = servers
+            .iter()
+            .filter(|(_, info)| !info.expired(self.config.ttl, now))
+            .map(|(a, i)| (*a, i.clone()))
+            .collect();
+        list.sort_by_key(|(addr, info)| (info.srtt, *addr));
+        list
+    }
+
+    pub fn is_down(&self, addr: IpAddr) -> bool {
+        self.get(addr)
+            .and_then(|info| info.down_until)
+            .is_some_and(|until| until > Instant::now())
+    }
+
+    /// Servers in the order they should be asked: by round trip time,
+    /// except that sometimes a random one goes first
+    pub fn order(&self, servers: &[IpAddr]) -> Vec<IpAddr> {
+        let mut rng = thread_rng();
+        let mut ordered = self.order_at(servers, Instant::now());
+        if ordered.len() > 1 && rng.gen_bool(self.config.exploration.clamp(0.0, 1.0)) {
+            let pick = rng.gen_range(1..ordered.len());
+            let explored = ordered.remove(pick);
+            ordered.insert(0, explored);
+        }
+        ordered
+    }
+
+    fn order_at(&self, servers: &[IpAddr], now: Instant) -> Vec<IpAddr> {
+        let known = self.servers.lock().unwrap();
+
+        let mut ordered = servers.to_vec();
+        // stable, so servers that look alike keep the given order
+        ordered.sort_by_key(|addr| {
+            known
+                .get(addr)
+                .filter(|info| !info.expired(self.config.ttl, now))
+                .map_or(UNKNOWN_RTT, |info| {
+                    info.effective_rtt(self.config.half_life, now)
+                })
+        });
+        ordered
+    }
+
+    /// the server responded after `rtt`
+    pub fn success(&self, addr: IpAddr, rtt: Duration) {
+        let now = Instant::now();
+        let mut servers = self.servers.lock().unwrap();
+        let info = self.update(&mut servers, addr, now);
+        info.sample(rtt, now);
+        info.failures = 0;
+        info.down_until = None;
+    }
+
+    /// a query to the server failed or timed out after `waited`
+    pub fn failure(&self, addr: IpAddr, waited: Duration) {
+        let now = Instant::now();
+        let mut servers = self.servers.lock().unwrap();
+        let info = self.update(&mut servers, addr, now);
+        info.sample(waited.max(info.srtt), now);
+        info.errors += 1;
+        info.failures += 1;
+    }
+
+    /// stop asking the server for a while
+    pub fn mark_down(&self, addr: IpAddr) {
+        let now = Instant::now();
+        let mut servers = self.servers.lock().unwrap();
+        let info = self.update(&mut servers, addr, now);
+        info.down_until = Some(now + self.config.down_time);
+    }
+
+    /// whether queries to the server should be sent without case
+    /// randomization
+    pub fn ignores_case(&self, addr: IpAddr) -> bool {
+        self.get(addr)
+            .and_then(|info| info.ignores_case_until)
+            .is_some_and(|until| until > Instant::now())
+    }
+
+    /// the server echoed a case randomized question in a single case,
+    /// true once that happened often enough in a row to stop randomizing
+    pub fn case_folded(&self, addr: IpAddr) -> bool {
+        let now = Instant::now();
+        let mut servers = self.servers.lock().unwrap();
+        let info = self.update(&mut servers, addr, now);
+        info.case_folds += 1;
+        if info.case_folds < CASE_FOLD_CONFIRMATIONS {
+            return false;
+        }
+        info.case_folds = 0;
+        info.ignores_case_until = Some(now + self.config.ignores_case_time);
+        true
+    }
+
+    /// the server echoed a case randomized question as it was sent
+    pub fn case_preserved(&self, addr: IpAddr) {
+        if let Some(info) = self.servers.lock().unwrap().get_mut(&addr) {
+            info.case_folds = 0;
+        }
+    }
+
+    /// the entry of `addr` to update, a new one if it expired. Once per
+    /// TTL this also forgets all servers that weren't queried for longer
+    /// than that, so the map doesn't grow without bound.
+    fn update<'a>(
+        &self,
+        servers: &'a mut HashMap<IpAddr, ServerInfo>,
+        addr: IpAddr,
+        now: Instant,
+    ) -> &'a mut ServerInfo {
+        let mut next_sweep = self.next_sweep.lock().unwrap();
+        if now >= *next_sweep {
+            *next_sweep = now + self.config.ttl;
+            servers.retain(|_, info| !info.expired(self.config.ttl, now));
+        }
+
+        let info = servers.entry(addr).or_insert_with(|| ServerInfo::new(now));
+        if info.expired(self.config.ttl, now) {
+            *info = ServerInfo::new(now);
+        }
+        info
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{InfraCache, InfraConfig};
+    use std::net::IpAddr;
+    use std::time::{Duration, Instant};
+
+    fn addr(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn prefer_fastest() {
+        let infra = InfraCache::new(InfraConfig {
+            exploration: 0.0,
+            ..InfraConfig::default()
+        });
+        let (far, near, new) = (addr("192.0.2.1"), addr("192.0.2.2"), addr("192.0.2.3"));
+        infra.success(far, Duration::from_millis(900));
+        infra.success(near, Duration::from_millis(20));
+        infra.success(near, Duration::from_millis(40));
+
+        let srtt = infra.get(near).unwrap().srtt;
+        assert!(srtt > Duration::from_millis(25) && srtt < Duration::from_millis(27));
+        // servers never measured are tried before known slow ones
+        assert_eq!(infra.order(&[far, new, near]), vec![near, new, far]);
+
+        infra.failure(near, Duration::from_secs(4));
+        assert_eq!(infra.order(&[far, near]), vec![far, near]);
+        let info = infra.get(near).unwrap();
+        assert_eq!((info.queries, info.errors, info.failures), (3, 1, 1));
+
+        // measurements fade, the slow server gets another chance later
+        let later = Instant::now() + Duration::from_secs(600);
+        assert_eq!(infra.order_at(&[far, new], later), vec![far, new]);
+
+        assert_eq!(infra.snapshot()[0].0, far);
+    }
+
+    #[test]
+    fn explore() {
+        let infra = InfraCache::new(InfraConfig {
+            exploration: 1.0,
+            ..InfraConfig::default()
+        });
+        let (fast, slow) = (addr("192.0.2.1"), addr("192.0.2.2"));
+        infra.success(fast, Duration::from_millis(10));
+        infra.success(slow, Duration::from_millis(500));
+        assert_eq!(infra.order(&[fast, slow]), vec![slow, fast]);
+    }
+
+    #[test]
+    fn forget_old_entries() {
+        let infra = InfraCache::new(InfraConfig {
+            ttl: Duration::ZERO,
+            ..InfraConfig::default()
+        });
+        infra.success(addr("192.0.2.1"), Duration::from_millis(10));
+        assert!(infra.snapshot().is_empty());
+    }
+
+    #[test]
+    fn confirm_ignored_case() {
+        let infra = InfraCache::new(InfraConfig {
+            ignores_case_time: Duration::from_millis(50),
+            ..InfraConfig::default()
+        });
+        let server = addr("192.0.2.1");
+
+        // a server that preserves case in between starts over
+        assert!(!infra.case_folded(server));
+        infra.case_preserved(server);
+        assert!(!infra.case_folded(server));
+        assert!(!infra.ignores_case(server));
+
+        assert!(infra.case_folded(server));
+        assert!(infra.ignores_case(server));
+
+        // and is randomized for again later
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(!infra.ignores_case(server));
+    }
+
+    #[test]
+    fn sweep_expired_entries() {
+        let infra = InfraCache::new(InfraConfig {
+            ttl: Duration::from_millis(50),
+            ..InfraConfig::default()
+        });
+        let (old, new) = (addr("192.0.2.1"), addr("192.0.2.2"));
+        infra.failure(old, Duration::from_secs(2));
+        std::thread::sleep(Duration::from_millis(60));
+
+        // an expired entry starts over instead of being updated
+        infra.success(new, Duration::from_millis(10));
+        assert_eq!(infra.servers.lock().unwrap().len(), 1);
+        infra.success(old, Duration::from_millis(10));
+        let info = infra.get(old).unwrap();
+        assert_eq!((info.queries, info.errors), (1, 0));
+    }
+}
@@ -0,0 +1,424 @@
+//! JSON representation of DNS messages following RFC 8427.
+//!
+//! Header fields, sections and resource records use the RFC 8427 member
+//! names (`ID`, `QR`, `questionRRs`, `answerRRs`, `NAME`, `TTL`, ...). Known
+//! record types carry their RDATA in presentation format under `rdata<TYPE>`
+//! (for example `rdataA` or `rdataMX`); other types use `RDLENGTH` and
+//! `RDATAHEX`.
+use serde_json::{json, Map, Value};
+use std::io::Cursor;
+
+use crate::dns::{DnsHeader, DnsPacket, DnsQuestion, DnsRecord, QueryType, ResultCode};
+use crate::packet::{PacketReader, PacketWriter, ReadSeek};
+use crate::rdata::{self, RdataReader};
+use crate::zone::ZoneParser;
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+/// convert a packet to its RFC 8427 JSON object
+pub fn to_json(packet: &DnsPacket) -> Value {
+    let header = &packet.header;
+    json!({
+        "ID": header.id,
+        "QR": header.qr,
+        "Opcode": header.opcode,
+        "AA": header.aa,
+        "TC": header.tc,
+        "RD": header.rd,
+        "RA": header.ra,
+        "AD": header.ad,
+        "CD": header.cd,
+        "RCODE": header.rcode as u8,
+        "QDCOUNT": packet.questions.len(),
+        "ANCOUNT": packet.answers.len(),
+        "NSCOUNT": packet.authorities.len(),
+        "ARCOUNT": packet.resources.len(),
+        "questionRRs": packet.questions.iter().map(question_to_json).collect::<Vec<_>>(),
+        "answerRRs": packet.answers.iter().map(record_to_json).collect::<Vec<_>>(),
+        "authorityRRs": packet.authorities.iter().map(record_to_json).collect::<Vec<_>>(),
+        "additionalRRs": packet.resources.iter().map(record_to_json).collect::<Vec<_>>(),
+    })
+}
+
+/// build a packet from an RFC 8427 JSON object
+pub fn from_json(value: &Value) -> Result<DnsPacket> {
+    let obj = value
+        .as_object()
+        .ok_or("DNS message must be a JSON object")?;
+
+    let mut header = DnsHeader::new();
+    header.id = get_int(obj, "ID")?.unwrap_or(0);
+    header.qr = get_flag(obj, "QR")?;
+    header.opcode = get_int(obj, "Opcode")?.unwrap_or(0);
+    if header.opcode > 15 {
+        return Err(format!("Opcode {} doesn't fit into 4 bits", header.opcode).into());
+    }
+    header.aa = get_flag(obj, "AA")?;
+    header.tc = get_flag(obj, "TC")?;
+    header.rd = get_flag(obj, "RD")?;
+    header.ra = get_flag(obj, "RA")?;
+    header.ad = get_flag(obj, "AD")?;
+    header.cd = get_flag(obj, "CD")?;
+    let rcode: u8 = get_int(obj, "RCODE")?.unwrap_or(0);
+    header.rcode = ResultCode::from_num(rcode);
+    if header.rcode as u8 != rcode {
+        return Err(format!("RCODE {} is not supported", rcode).into());
+    }
+
+    let mut packet = DnsPacket::new();
+    packet.header = header;
+
+    // a single question may also be given with the QNAME/QTYPE/QCLASS shorthand
+    if let Some(name) = obj.get("QNAME").and_then(Value::as_str) {
+        packet.questions.push(DnsQuestion {
+            name: normalize_name(name),
+            qtype: QueryType::from_num(get_int(obj, "QTYPE")?.unwrap_or(1)),
+            qclass: get_int(obj, "QCLASS")?.unwrap_or(1),
+        });
+    }
+    for q in get_array(obj, "questionRRs")? {
+        packet.questions.push(question_from_json(q)?);
+    }
+    for rr in get_array(obj, "answerRRs")? {
+        packet.answers.push(record_from_json(rr)?);
+    }
+    for rr in get_array(obj, "authorityRRs")? {
+        packet.authorities.push(record_from_json(rr)?);
+    }
+    for rr in get_array(obj, "additionalRRs")? {
+        packet.resources.push(record_from_json(rr)?);
+    }
+
+    packet.header.qd_count = packet.questions.len() as u16;
+    packet.header.an_count = packet.answers.len() as u16;
+    packet.header.ns_count = packet.authorities.len() as u16;
+    packet.header.ar_count = packet.resources.len() as u16;
+
+    Ok(packet)
+}
+
+fn question_to_json(question: &DnsQuestion) -> Value {
+    json!({
+        "NAME": question.name,
+        "TYPE": question.qtype.to_num(),
+        "CLASS": question.qclass,
+    })
+}
+
+fn question_from_json(value: &Value) -> Result<DnsQuestion> {
+    let obj = value.as_object().ok_or("question must be a JSON object")?;
+    Ok(DnsQuestion {
+        name: normalize_name(get_str(obj, "NAME")?),
+        qtype: QueryType::from_num(get_int(obj, "TYPE")?.ok_or("question without TYPE")?),
+        qclass: get_int(obj, "CLASS")?.unwrap_or(1),
+    })
+}
+
+fn record_to_json(record: &DnsRecord) -> Value {
+    let mut obj = Map::new();
+    obj.insert("NAME".into(), record.domain().into());
+    obj.insert("TYPE".into(), record.qtype().to_num().into());
+    obj.insert("CLASS".into(), 1.into());
+    obj.insert("TTL".into(), record.ttl().into());
+
+    match (record, record.rdata()) {
+        // CLASS and TTL carry EDNS parameters, the options go out as hex
+        (
+            DnsRecord::OPT {
+                packet_len, flags, ..
+            },
+            _,
+        ) => {
+            let mut wire = Vec::new();
+            if record.write(&mut PacketWriter::new(&mut wire)).is_ok() {
+                // past the root name and the fixed fields
+                let hex: String = wire[11..].iter().map(|b| format!("{:02X}", b)).collect();
+                obj.insert("CLASS".into(), (*packet_len).into());
+                obj.insert("TTL".into(), (*flags).into());
+                obj.insert("RDLENGTH".into(), (wire.len() - 11).into());
+                obj.insert("RDATAHEX".into(), hex.into());
+            }
+        }
+        (DnsRecord::UNKNOWN { data, .. }, _) => {
+            let hex: String = data.iter().map(|b| format!("{:02X}", b)).collect();
+            obj.insert("RDLENGTH".into(), data.len().into());
+            obj.insert("RDATAHEX".into(), hex.into());
+        }
+        // registered types have no mnemonic, so they go out as hex
+        (DnsRecord::CUSTOM { .. }, Some(rdata)) => {
+            let mut data = Vec::new();
+            if rdata.encode(&mut data).is_ok() {
+                let hex: String = data.iter().map(|b| format!("{:02X}", b)).collect();
+                obj.insert("RDLENGTH".into(), data.len().into());
+                obj.insert("RDATAHEX".into(), hex.into());
+            }
+        }
+        (_, Some(rdata)) => {
+            let member = format!("rdata{:?}", record.qtype());
+            obj.insert(member, rdata.presentation().into());
+        }
+        (_, None) => {}
+    }
+    Value::Object(obj)
+}
+
+fn record_from_json(value: &Value) -> Result<DnsRecord> {
+    let obj = value
+        .as_object()
+        .ok_or("resource record must be a JSON object")?;
+    let name = normalize_name(get_str(obj, "NAME")?);
+    let qtype: u16 = get_int(obj, "TYPE")?.ok_or("resource record without TYPE")?;
+    let ttl: u32 = get_int(obj, "TTL")?.unwrap_or(0);
+
+    if let Some(hex) = obj.get("RDATAHEX") {
+        let data = hex
+            .as_str()
+            .and_then(decode_hex)
+            .ok_or("RDATAHEX must be a hex string")?;
+        let len = u16::try_from(data.len()).map_err(|_| "RDATAHEX longer than 65535 octets")?;
+
+        if qtype == QueryType::OPT.to_num() {
+            let class: u16 = get_int(obj, "CLASS")?.unwrap_or(512);
+            let mut wire = vec![0];
+            wire.extend_from_slice(&qtype.to_be_bytes());
+            wire.extend_from_slice(&class.to_be_bytes());
+            wire.extend_from_slice(&ttl.to_be_bytes());
+            wire.extend_from_slice(&len.to_be_bytes());
+            wire.extend_from_slice(&data);
+            return DnsRecord::read(&mut PacketReader::new(Cursor::new(wire)));
+        }
+
+        let mut cursor = Cursor::new(data);
+        let mut reader: RdataReader = PacketReader::new(&mut cursor as &mut dyn ReadSeek);
+        if let Some(rdata) = rdata::decode(qtype, &mut reader, len)? {
+            return Ok(DnsRecord::from_rdata(name, ttl, rdata));
+        }
+        return Ok(DnsRecord::UNKNOWN {
+            domain: name,
+            qtype,
+            data: cursor.into_inner(),
+            ttl,
+        });
+    }
+
+    let Some((member, rdata)) = obj.iter().find(|(k, _)| k.starts_with("rdata")) else {
+        // the length alone doesn't say what the RDATA is
+        return match get_int::<u16>(obj, "RDLENGTH")? {
+            None | Some(0) => Ok(DnsRecord::UNKNOWN {
+                domain: name,
+                qtype,
+                data: Vec::new(),
+                ttl,
+            }),
+            Some(len) => Err(format!("{} octets of RDATA without RDATAHEX", len).into()),
+        };
+    };
+    let rdata = rdata
+        .as_str()
+        .ok_or_else(|| format!("{} must be a string", member))?;
+
+    // reuse the master file parser for the presentation format, for the
+    // RDATA only so that no directives get interpreted
+    let owner = if name.is_empty() {
+        ".".to_owned()
+    } else {
+        format!("{}.", name)
+    };
+    ZoneParser::new()
+        .origin(".")
+        .parse_rdata(&owner, ttl, &member["rdata".len()..], rdata)
+        .map_err(|e| format!("invalid {}: {}", member, e.message).into())
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn normalize_name(name: &str) -> String {
+    name.trim_end_matches('.').to_lowercase()
+}
+
+/// an unsigned integer member that has to fit into `T`
+fn get_int<T: TryFrom<u64>>(obj: &Map<String, Value>, key: &str) -> Result<Option<T>> {
+    let Some(v) = obj.get(key) else {
+        return Ok(None);
+    };
+    let v = v
+        .as_u64()
+        .ok_or_else(|| format!("{} must be an unsigned integer", key))?;
+    T::try_from(v)
+        .map(Some)
+        .map_err(|_| format!("{} {} is out of range", key, v).into())
+}
+
+fn get_str<'a>(obj: &'a Map<String, Value>, key: &str) -> Result<&'a str> {
+    obj.get(key)
+        .and_then(Value::as_str)
+        .ok_or_else(|| format!("{} must be a string", key).into())
+}
+
+/// flags are booleans, but integer 0/1 is accepted as well
+fn get_flag(obj: &Map<String, Value>, key: &str) -> Result<bool> {
+    match obj.get(key) {
+        None => Ok(false),
+        Some(Value::Bool(b)) => Ok(*b),
+        Some(v) => match v.as_u64() {
+            Some(0) => Ok(false),
+            Some(1) => Ok(true),
+            _ => Err(format!("{} must be a boolean", key).into()),
+        },
+    }
+}
+
+fn get_array<'a>(obj: &'a Map<String, Value>, key: &str) -> Result<&'a [Value]> {
+    match obj.get(key) {
+        None => Ok(&[]),
+        Some(Value::Array(a)) => Ok(a),
+        Some(_) => Err(format!("{} must be an array", key).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{from_json, to_json};
+    use crate::dns::{DnsPacket, DnsQuestion, DnsRecord, QueryType, ResultCode};
+    use crate::packet::{PacketReader, PacketWriter};
+    use serde_json::json;
+    use std::io::Cursor;
+
+    #[test]
+    fn round_trip() {
+        let mut packet = DnsPacket::new();
+        packet.header.id = 19678;
+        packet.header.qr = true;
+        packet.header.rd = true;
+        packet.header.rcode = ResultCode::NOERROR;
+        packet.questions.push(DnsQuestion {
+            name: "example.com".into(),
+            qtype: QueryType::MX,
+            qclass: 1,
+        });
+        packet.answers.push(DnsRecord::MX {
+            domain: "example.com".into(),
+            priority: 10,
+            host: "mail.example.com".into(),
+            ttl: 3600,
+        });
+        packet.answers.push(DnsRecord::TXT {
+            domain: "example.com".into(),
+            data: vec![b"v=spf1 -all".to_vec(), b"say \"hi\"".to_vec()],
+            ttl: 60,
+        });
+
+        let value = to_json(&packet);
+        assert_eq!(value["ID"], 19678);
+        assert_eq!(value["QR"], true);
+        assert_eq!(value["ANCOUNT"], 2);
+        assert_eq!(value["questionRRs"][0]["NAME"], "example.com");
+        assert_eq!(value["answerRRs"][0]["rdataMX"], "10 mail.example.com.");
+        assert_eq!(
+            value["answerRRs"][1]["rdataTXT"],
+            "\"v=spf1 -all\" \"say \\\"hi\\\"\""
+        );
+
+        let back = from_json(&value).unwrap();
+        assert_eq!(back.header.id, 19678);
+        assert_eq!(back.questions, packet.questions);
+        assert_eq!(
+            serde_json::to_value(&back.answers).unwrap(),
+            serde_json::to_value(&packet.answers).unwrap()
+        );
+    }
+
+    #[test]
+    fn query_shorthand() {
+        let packet = from_json(&json!({
+            "ID": 1, "QR": 0, "Opcode": 0, "RD": 1,
+            "QNAME": "example.com.", "QTYPE": 28, "QCLASS": 1,
+        }))
+        .unwrap();
+        assert!(packet.header.rd);
+        assert_eq!(packet.header.qd_count, 1);
+        assert_eq!(packet.questions[0].name, "example.com");
+        assert_eq!(packet.questions[0].qtype, QueryType::AAAA);
+    }
+
+    #[test]
+    fn out_of_range_values() {
+        for value in [
+            json!({ "ID": 70000 }),
+            json!({ "Opcode": 16 }),
+            json!({ "RCODE": 6 }),
+            json!({ "RCODE": 300 }),
+            json!({ "QNAME": "example.com", "QTYPE": 65536 }),
+            json!({ "questionRRs": [{ "NAME": "example.com", "TYPE": 1, "CLASS": 65536 }] }),
+            json!({ "answerRRs": [{ "NAME": "example.com", "TYPE": 1, "TTL": 4294967296u64, "rdataA": "192.0.2.1" }] }),
+            json!({ "answerRRs": [{ "NAME": "example.com", "TYPE": 99, "RDLENGTH": 65536 }] }),
+        ] {
+            assert!(from_json(&value).is_err(), "{} accepted", value);
+        }
+        assert_eq!(
+            from_json(&json!({ "ID": 65535, "Opcode": 15, "RCODE": 5 }))
+                .unwrap()
+                .header
+                .id,
+            65535
+        );
+    }
+
+    #[test]
+    fn rdata_is_not_a_zone_file() {
+        // an empty file, so that including it would go unnoticed
+        let path = std::env::temp_dir().join(format!("json-include-{}", std::process::id()));
+        std::fs::write(&path, "").unwrap();
+        let include = format!("192.0.2.1\n$INCLUDE {}", path.display());
+        for rdata in [
+            include.as_str(),
+            "192.0.2.1\nevil.example. 60 IN A 192.0.2.66",
+        ] {
+            let value = json!({
+                "answerRRs": [{ "NAME": "example.com", "TYPE": 1, "TTL": 60, "rdataA": rdata }],
+            });
+            assert!(from_json(&value).is_err());
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        let value = json!({
+            "answerRRs": [{ "NAME": "example.com", "TYPE": 15, "TTL": 60, "rdataMX": "10 mail.example.com." }],
+        });
+        let packet = from_json(&value).unwrap();
+        assert!(
+            matches!(&packet.answers[0], DnsRecord::MX { host, .. } if host == "mail.example.com")
+        );
+    }
+
+    #[test]
+    fn edns() {
+        let opt = DnsRecord::OPT {
+            packet_len: 1232,
+            flags: 0x8000,
+            options: vec![(11, vec![0, 100])],
+        };
+        let mut packet = DnsPacket::query("example.com", QueryType::A)
+            .additional(opt.clone())
+            .build();
+
+        let mut wire = Vec::new();
+        packet.write(&mut PacketWriter::new(&mut wire)).unwrap();
+        let parsed = DnsPacket::from_buffer(&mut PacketReader::new(Cursor::new(wire))).unwrap();
+        assert_eq!(parsed.edns(), Some(&opt));
+        assert_eq!(parsed.edns_option(11), Some(&[0, 100][..]));
+
+        let value = to_json(&parsed);
+        assert_eq!(value["additionalRRs"][0]["CLASS"], 1232);
+        assert_eq!(value["additionalRRs"][0]["RDATAHEX"], "000B00020064");
+        assert_eq!(from_json(&value).unwrap().edns(), Some(&opt));
+    }
+}
@@ -1,2 +1,17 @@
+pub mod builder;
+pub mod cache;
 pub mod dns;
+pub mod infra;
+#[cfg(feature = "serde")]
+pub mod json;
 pub mod packet;
+pub mod pool;
+pub mod rdata;
+pub mod resolver;
+pub mod roothints;
+pub mod rrset;
+pub mod tcp;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod transport;
+pub mod zone;
@@ -1,183 +1,317 @@
-use dns::dns::{DnsPacket, DnsQuestion, QueryType, ResultCode};
+use dns::dns::{DnsPacket, DnsRecord, ResultCode};
 use dns::packet::{PacketReader, PacketWriter};
-use rand::{thread_rng, Rng};
+use dns::resolver::{Resolver, ResolverConfig};
+use dns::roothints::RootHints;
+use dns::tcp::{FramedReader, FramedWriter};
 use std::collections::VecDeque;
-use std::io::Cursor;
-use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::io::{self, Cursor};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Condvar, Mutex};
 use std::thread::Builder;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 type Error = Box<dyn std::error::Error>;
 type Result<T> = std::result::Result<T, Error>;
 
-/// Recursive lookup name
-fn recursive_lookup(qname: &str, qtype: QueryType) -> Result<DnsPacket> {
-    // starting with a root server
-    // https://www.internic.net/domain/named.root
-    let mut ns = "198.41.0.4".parse::<Ipv4Addr>().unwrap();
+/// largest UDP response to clients without EDNS
+const UDP_PAYLOAD: usize = 512;
 
-    let mut name = qname.to_owned();
+/// largest UDP response we send to EDNS clients, and advertise
+const EDNS_PAYLOAD: u16 = 1232;
 
-    loop {
-        println!("attempting lookup of {:?} {} with ns {}", qtype, name, ns);
+/// how long an idle TCP connection is kept open
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
 
-        let server = (ns, 53);
-        let response = lookup(name.as_str(), qtype, server)?;
+/// how long a connection waits for outstanding responses after the last
+/// query before it is closed anyway
+const TCP_RESPONSE_DEADLINE: Duration = Duration::from_secs(30);
 
-        if !response.answers.is_empty() && response.header.rcode == ResultCode::NOERROR {
-            // if name servers not return any A record, and have CNAME record,
-            // try to lookup it instead.
-            if let Some(cname) = response.get_first_cname() {
-                name = cname;
-                continue;
-            }
-            // find it
-            if response.have_a() {
-                return Ok(response);
-            }
-        }
+/// TCP connections served at the same time, more are refused
+const MAX_TCP_CONNECTIONS: usize = 100;
 
-        // the authoritative name servers telling us that the name doesn't exist.
-        if response.header.rcode == ResultCode::NXDOMAIN {
-            return Ok(response);
-        }
+/// EDNS option code of edns-tcp-keepalive (RFC 7828)
+const EDNS_TCP_KEEPALIVE: u16 = 11;
 
-        // fast path: find a new nameserver based on NS and a corresponding A
-        // record in the additional section.
-        if let Some(resolved_ns) = response.get_resolved_ns(name.as_str()) {
-            ns = resolved_ns;
-            continue;
-        }
+/// Prints what the resolver library logs, warnings and errors to stderr.
+struct Logger;
 
-        // slow path: have to resolve the ip of a NS record.
-        let unresolved_ns = match response.get_unresolved_ns(name.as_str()) {
-            Some(x) => x,
-            None => return Ok(response),
-        };
-
-        // lookup the IP of an name server.
-        let recursive_response = recursive_lookup(unresolved_ns, QueryType::A)?;
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.target().starts_with("dns")
+    }
 
-        if let Some(new_ns) = recursive_response.get_first_a() {
-            ns = new_ns;
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        if record.level() <= log::Level::Warn {
+            eprintln!("{}", record.args());
         } else {
-            return Ok(response);
+            println!("{}", record.args());
         }
     }
-}
-
-/// Forwarded query to a delegate name server
-fn lookup(qname: &str, qtype: QueryType, server: (Ipv4Addr, u16)) -> Result<DnsPacket> {
-    let socket = UdpSocket::bind(("0.0.0.0", 0))?;
-
-    // would block the execution because the data is
-    // not ready to be read or the operation is not
-    // cannot be completed immediately, so we need
-    // to set read/write timeout
-    socket.set_read_timeout(Some(Duration::from_secs(1)))?;
-    socket.set_write_timeout(Some(Duration::from_secs(1)))?;
 
-    let mut packet = DnsPacket::new();
-
-    let mut question = DnsQuestion::new();
-    question.name = qname.to_string();
-    question.qtype = qtype;
-    question.qclass = 1;
+    fn flush(&self) {}
+}
 
-    let mut rng = thread_rng();
-    let id: u16 = rng.gen_range(1..=10000);
+static LOGGER: Logger = Logger;
 
-    packet.header.id = id;
-    packet.header.qd_count = 1;
-    packet.header.rd = true;
-    packet.questions.push(question);
+/// Where a request came from, and so where its response goes.
+enum Client {
+    Udp(SocketAddr),
+    Tcp(Arc<TcpConnection>),
+}
 
-    let mut w = vec![0; 64];
-    let mut req_buffer = PacketWriter::new(Cursor::new(&mut w));
-    packet.write(&mut req_buffer)?;
-    socket.send_to(&w, server)?;
+/// A client's TCP connection, shared between its reader thread and the
+/// handlers answering its queries.
+struct TcpConnection {
+    peer: SocketAddr,
+    writer: Mutex<FramedWriter<TcpStream>>,
+    /// queries read but not answered yet
+    in_flight: Mutex<usize>,
+    /// signalled whenever a query was answered
+    answered: Condvar,
+}
 
-    let mut rv = vec![0; 512];
-    socket.recv_from(&mut rv)?;
-    let mut buffer = PacketReader::new(Cursor::new(&mut rv));
+/// Send the response over the transport the request came in on
+fn send_response(
+    socket: &UdpSocket,
+    client: &Client,
+    request: &DnsPacket,
+    mut packet: DnsPacket,
+) -> Result<()> {
+    match client {
+        Client::Udp(src) => {
+            // the client's EDNS buffer size, within what we are willing
+            // to send
+            let limit = match request.edns() {
+                Some(DnsRecord::OPT { packet_len, .. }) => {
+                    (*packet_len).clamp(UDP_PAYLOAD as u16, EDNS_PAYLOAD) as usize
+                }
+                _ => UDP_PAYLOAD,
+            };
 
-    let packet = DnsPacket::from_buffer(&mut buffer);
-    println!("response from public DNS: {:?}", packet);
+            let mut w = Vec::with_capacity(limit);
+            let len = packet.clone().write(&mut PacketWriter::new(&mut w))?;
+            if len > limit {
+                // too big, the client has to come back over TCP
+                packet.header.tc = true;
+                packet.answers.clear();
+                packet.authorities.clear();
+                packet
+                    .resources
+                    .retain(|r| matches!(r, DnsRecord::OPT { .. }));
+                w.clear();
+                packet.write(&mut PacketWriter::new(&mut w))?;
+            }
 
-    if let Ok(ref packet) = packet {
-        assert_eq!(packet.header.id, id);
+            println!("write packet: {:?}", w);
+            socket.send_to(&w, src)?;
+        }
+        Client::Tcp(conn) => {
+            let result = conn.writer.lock().unwrap().write_message(&packet);
+            *conn.in_flight.lock().unwrap() -= 1;
+            conn.answered.notify_all();
+            result?;
+        }
     }
 
-    packet
+    Ok(())
 }
 
 /// Handle a single incoming packet
-fn handle_request(socket: &UdpSocket, src: SocketAddr, mut request: DnsPacket) -> Result<()> {
-    // initialize response packet
-    let mut packet = DnsPacket::new();
-    // make sure use the same id as request
-    packet.header.id = request.header.id;
-    packet.header.rd = true;
-    packet.header.ra = true;
-    packet.header.qr = true;
+fn handle_request(
+    resolver: &Resolver,
+    socket: &UdpSocket,
+    client: &Client,
+    request: DnsPacket,
+) -> Result<()> {
+    // clients send edns-tcp-keepalive empty and only over TCP (RFC 7828)
+    let keepalive = request.edns_option(EDNS_TCP_KEEPALIVE);
+    let bad_keepalive = match client {
+        Client::Udp(_) => keepalive.is_some(),
+        Client::Tcp(_) => keepalive.is_some_and(|data| !data.is_empty()),
+    };
 
     // normal case, exactly one question is present
-    if let Some(question) = request.questions.pop() {
+    let mut packet = if let (Some(question), false) = (request.questions.first(), bad_keepalive) {
         println!("received query: {:?}", question);
 
-        match recursive_lookup(&question.name, question.qtype) {
-            Ok(result) => {
-                packet.questions.push(question);
-                packet.header.rcode = result.header.rcode;
+        // make sure use the same id and question as request
+        let response = DnsPacket::response_to(&request)
+            .recursion_desired(true)
+            .recursion_available(true);
 
-                for rec in result.answers {
+        match resolver.resolve(&question.name, question.qtype) {
+            Ok(result) => {
+                for rec in &result.answers {
                     println!("answer: {:?}", rec);
-                    packet.answers.push(rec);
                 }
-                for rec in result.authorities {
+                for rec in &result.authorities {
                     println!("authority: {:?}", rec);
-                    packet.authorities.push(rec);
                 }
-                for rec in result.resources {
+                for rec in &result.additionals {
                     println!("resource: {:?}", rec);
-                    packet.resources.push(rec);
                 }
+                response
+                    .rcode(result.rcode)
+                    .answers(result.answers)
+                    .authorities(result.authorities)
+                    .additionals(result.additionals)
+                    .build()
             }
             Err(e) => {
                 println!("lookup error: {}", e);
-                packet.header.rcode = ResultCode::SERVFAIL;
+                response.rcode(ResultCode::SERVFAIL).build()
             }
         }
     }
-    // make sure that a question is actually present
+    // make sure that a question is actually present, and the request is
+    // well formed
     else {
-        packet.header.rcode = ResultCode::FORMERR;
+        DnsPacket::response_to(&request)
+            .recursion_desired(true)
+            .recursion_available(true)
+            .rcode(ResultCode::FORMERR)
+            .build()
+    };
+
+    // answer EDNS with EDNS, and tell TCP clients asking for it how long
+    // an idle connection stays open, in units of 100 milliseconds
+    if request.edns().is_some() {
+        let mut options = Vec::new();
+        if matches!(client, Client::Tcp(_)) && keepalive.is_some() && !bad_keepalive {
+            let timeout = (TCP_IDLE_TIMEOUT.as_millis() / 100) as u16;
+            options.push((EDNS_TCP_KEEPALIVE, timeout.to_be_bytes().to_vec()));
+        }
+        packet.resources.push(DnsRecord::OPT {
+            packet_len: EDNS_PAYLOAD,
+            flags: 0,
+            options,
+        });
     }
 
-    let mut w = vec![0; 4096];
-    let mut res_buffer = PacketWriter::new(Cursor::new(&mut w));
+    send_response(socket, client, &request, packet)
+}
 
-    let len = packet.write(&mut res_buffer)?;
-    let data = &res_buffer.get_ref()[..len];
+/// Read pipelined queries from a TCP client until it closes its end of
+/// the connection or stays idle for too long. Responses are written by
+/// the handlers as they are done, so they may be out of order.
+fn serve_tcp(
+    stream: TcpStream,
+    queue: &Mutex<VecDeque<(Client, DnsPacket)>>,
+    cond: &Condvar,
+) -> Result<()> {
+    let peer = stream.peer_addr()?;
+    stream.set_read_timeout(Some(TCP_IDLE_TIMEOUT))?;
+    stream.set_write_timeout(Some(TCP_IDLE_TIMEOUT))?;
+    stream.set_nodelay(true)?;
+
+    let conn = Arc::new(TcpConnection {
+        peer,
+        writer: Mutex::new(FramedWriter::new(stream.try_clone()?)),
+        in_flight: Mutex::new(0),
+        answered: Condvar::new(),
+    });
+    let mut reader = FramedReader::new(stream);
+    let mut last_request = Instant::now();
+
+    let result = loop {
+        let request = match reader.read_message() {
+            Ok(Some(request)) => request,
+            Ok(None) => {
+                // the client is done sending, but still gets the answers
+                // to what it sent
+                let _ = reader.get_ref().shutdown(Shutdown::Read);
+                let deadline = last_request + TCP_RESPONSE_DEADLINE;
+                let mut in_flight = conn.in_flight.lock().unwrap();
+                while *in_flight > 0 {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    if left.is_zero() {
+                        break;
+                    }
+                    in_flight = conn.answered.wait_timeout(in_flight, left).unwrap().0;
+                }
+                break Ok(());
+            }
+            Err(e) => {
+                let idle = e.downcast_ref::<io::Error>().is_some_and(|e| {
+                    matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    )
+                });
+                // keep the connection while responses are outstanding,
+                // but not forever
+                if idle
+                    && *conn.in_flight.lock().unwrap() > 0
+                    && last_request.elapsed() < TCP_RESPONSE_DEADLINE
+                {
+                    continue;
+                }
+                if idle {
+                    println!("closing idle connection from {}", conn.peer);
+                    break Ok(());
+                }
+                break Err(e);
+            }
+        };
 
-    println!("write packet: {:?}", data);
-    socket.send_to(data, src)?;
+        last_request = Instant::now();
+        *conn.in_flight.lock().unwrap() += 1;
+        queue
+            .lock()
+            .unwrap()
+            .push_back((Client::Tcp(conn.clone()), request));
+        cond.notify_one();
+    };
+
+    // handlers that are still busy past the deadline fail to write from
+    // here on, rather than keeping the connection open
+    let _ = reader.get_ref().shutdown(Shutdown::Both);
+    result
+}
 
-    Ok(())
+/// Take requests off the queue and answer them, forever
+fn handle_requests(
+    resolver: &Resolver,
+    socket: &UdpSocket,
+    queue: &Mutex<VecDeque<(Client, DnsPacket)>>,
+    cond: &Condvar,
+) {
+    loop {
+        let (client, request) = {
+            let mut queue = queue.lock().unwrap();
+            while queue.is_empty() {
+                queue = cond.wait(queue).unwrap();
+            }
+            match queue.pop_front() {
+                Some(x) => x,
+                None => continue,
+            }
+        };
+        match handle_request(resolver, socket, &client, request) {
+            Ok(_) => println!("handle query success"),
+            Err(e) => eprintln!("failed to handle request: {}", e),
+        }
+    }
 }
 
-/// Accepts DNS queries through UDP. Packets are read on a single thread,
-/// and a new thread is spawned to handle the request asynchronously.
+/// Accepts DNS queries through UDP and TCP on the same port. Packets are
+/// read on a single thread per socket or connection, and handed to a pool
+/// of threads that handle the requests asynchronously.
 pub struct DnsUdpServer {
-    request_queue: Arc<Mutex<VecDeque<(SocketAddr, DnsPacket)>>>,
+    resolver: Arc<Resolver>,
+    request_queue: Arc<Mutex<VecDeque<(Client, DnsPacket)>>>,
     request_cond: Arc<Condvar>,
     thread_count: usize,
 }
 
 impl DnsUdpServer {
-    pub fn new(thread_count: usize) -> DnsUdpServer {
+    pub fn new(resolver: Resolver, thread_count: usize) -> DnsUdpServer {
         DnsUdpServer {
+            resolver: Arc::new(resolver),
             request_queue: Arc::new(Mutex::new(VecDeque::new())),
             request_cond: Arc::new(Condvar::new()),
             thread_count,
@@ -200,39 +334,61 @@ impl DnsUdpServer {
 
             let request_cond = self.request_cond.clone();
             let request_queue = self.request_queue.clone();
+            let resolver = self.resolver.clone();
 
             let name = format!("handler-{}", thread_id);
             let jh = Builder::new()
                 .name(name)
                 .spawn(move || {
-                    loop {
-                        // 1. acquire lock
-                        // 2. wait on the condition until data is available
-                        // 3. handle request in queue.
-                        let (src, request) = match request_queue
-                            .lock()
-                            .ok()
-                            .and_then(|x| request_cond.wait(x).ok())
-                            .and_then(|mut x| x.pop_front())
-                        {
-                            Some(x) => x,
-                            None => {
-                                unreachable!();
-                            }
-                        };
-                        match handle_request(&socket_clone, src, request) {
-                            Ok(_) => println!("handle query success"),
-                            Err(e) => {
-                                eprintln!("failed to handle request: {}", e);
-                                continue;
-                            }
-                        }
-                    }
+                    handle_requests(&resolver, &socket_clone, &request_queue, &request_cond)
                 })
                 .unwrap();
             handlers.push(jh);
         }
 
+        // accept TCP connections, each read on its own thread
+        let listener = TcpListener::bind(("0.0.0.0", 5300)).unwrap();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let request_queue = self.request_queue.clone();
+        let request_cond = self.request_cond.clone();
+        let jh = Builder::new()
+            .name("tcp-incoming".into())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            eprintln!("failed to accept TCP connection: {}", e);
+                            continue;
+                        }
+                    };
+
+                    if connections.fetch_add(1, Ordering::SeqCst) >= MAX_TCP_CONNECTIONS {
+                        connections.fetch_sub(1, Ordering::SeqCst);
+                        eprintln!(
+                            "too many TCP connections, refusing {:?}",
+                            stream.peer_addr()
+                        );
+                        continue;
+                    }
+
+                    let connections = connections.clone();
+                    let request_queue = request_queue.clone();
+                    let request_cond = request_cond.clone();
+                    let spawned = Builder::new().name("tcp-connection".into()).spawn(move || {
+                        if let Err(e) = serve_tcp(stream, &request_queue, &request_cond) {
+                            eprintln!("TCP connection failed: {}", e);
+                        }
+                        connections.fetch_sub(1, Ordering::SeqCst);
+                    });
+                    if let Err(e) = spawned {
+                        eprintln!("failed to spawn TCP connection thread: {}", e);
+                    }
+                }
+            })
+            .unwrap();
+        handlers.push(jh);
+
         // handle incoming dns query request
         let jh = Builder::new()
             .name("incoming".into())
@@ -240,18 +396,29 @@ impl DnsUdpServer {
                 loop {
                     let mut w = vec![0; 512];
 
-                    let (_, src) = socket.recv_from(&mut w).expect("recv failed");
+                    let (_, src) = match socket.recv_from(&mut w) {
+                        Ok(x) => x,
+                        Err(e) => {
+                            eprintln!("failed to receive UDP packet: {}", e);
+                            continue;
+                        }
+                    };
 
                     let mut req_buffer = PacketReader::new(Cursor::new(&mut w));
-                    let request =
-                        DnsPacket::from_buffer(&mut req_buffer).expect("parse dns packet failed");
+                    let request = match DnsPacket::from_buffer(&mut req_buffer) {
+                        Ok(x) => x,
+                        Err(e) => {
+                            eprintln!("failed to parse packet from {}: {}", src, e);
+                            continue;
+                        }
+                    };
 
                     // 1. acquire lock
                     // 2. add request to queue
                     // 3. notify waiting threads
                     match self.request_queue.lock() {
                         Ok(mut queue) => {
-                            queue.push_back((src, request));
+                            queue.push_back((Client::Udp(src), request));
                             self.request_cond.notify_one();
                         }
                         Err(e) => {
@@ -270,8 +437,216 @@ impl DnsUdpServer {
 }
 
 fn main() -> Result<()> {
-    let server = DnsUdpServer::new(5);
+    log::set_logger(&LOGGER).map_err(|e| e.to_string())?;
+    log::set_max_level(log::LevelFilter::Debug);
+
+    let mut config = ResolverConfig::default();
+
+    // an optional named.root file replaces the compiled-in root hints,
+    // e.g. to point at a private root
+    if let Some(path) = std::env::args().nth(1) {
+        config.root_hints = RootHints::from_file(&path)?;
+        println!(
+            "loaded {} root servers from {}",
+            config.root_hints.servers.len(),
+            path
+        );
+    }
+
+    let resolver = Resolver::new(config);
+    match resolver.prime() {
+        Ok(hints) => println!(
+            "primed {} root servers ({} addresses)",
+            hints.servers.len(),
+            hints.addrs().len()
+        ),
+        Err(e) => eprintln!(
+            "root priming failed, resolving from root hints until it succeeds: {}",
+            e
+        ),
+    }
+
+    let server = DnsUdpServer::new(resolver, 5);
     server.run();
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dns::dns::QueryType;
+    use dns::transport::Transport;
+    use std::net::Ipv4Addr;
+    use std::thread;
+
+    /// Answers address queries for any name with 192.0.2.1, taking its
+    /// time for names under slow.example.
+    struct Authoritative;
+
+    impl Transport for Authoritative {
+        fn query(
+            &self,
+            query: &DnsPacket,
+            _server: SocketAddr,
+            _timeout: Duration,
+        ) -> Result<DnsPacket> {
+            let name = &query.questions[0].name;
+            if name.ends_with("slow.example") {
+                thread::sleep(Duration::from_millis(300));
+            }
+            Ok(DnsPacket::response_to(query)
+                .authoritative(true)
+                .answer(DnsRecord::A {
+                    domain: name.clone(),
+                    addr: Ipv4Addr::new(192, 0, 2, 1),
+                    ttl: 300,
+                })
+                .build())
+        }
+
+        fn query_tcp(
+            &self,
+            query: &DnsPacket,
+            server: SocketAddr,
+            timeout: Duration,
+        ) -> Result<DnsPacket> {
+            self.query(query, server, timeout)
+        }
+    }
+
+    fn resolver() -> Resolver {
+        let config = ResolverConfig {
+            root_hints: RootHints::from_addrs(["198.41.0.4".parse().unwrap()]),
+            ..ResolverConfig::default()
+        };
+        Resolver::with_transport(config, Arc::new(Authoritative))
+    }
+
+    fn query(id: u16, name: &str, options: Vec<(u16, Vec<u8>)>) -> DnsPacket {
+        DnsPacket::query(name, QueryType::A)
+            .id(id)
+            .additional(DnsRecord::OPT {
+                packet_len: 1232,
+                flags: 0,
+                options,
+            })
+            .build()
+    }
+
+    /// a server with two handlers serving a single TCP connection, and
+    /// the client's end of it
+    fn tcp_server() -> (TcpStream, thread::JoinHandle<()>) {
+        let resolver = Arc::new(resolver());
+        let queue = Arc::new(Mutex::new(VecDeque::new()));
+        let cond = Arc::new(Condvar::new());
+        for _ in 0..2 {
+            let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+            let (resolver, queue, cond) = (resolver.clone(), queue.clone(), cond.clone());
+            thread::spawn(move || handle_requests(&resolver, &socket, &queue, &cond));
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            serve_tcp(stream, &queue, &cond).unwrap();
+        });
+
+        let stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        (stream, server)
+    }
+
+    #[test]
+    fn pipelined_tcp_queries() {
+        let (stream, server) = tcp_server();
+        let mut writer = FramedWriter::new(stream.try_clone().unwrap());
+        let keepalive = vec![(EDNS_TCP_KEEPALIVE, Vec::new())];
+        writer
+            .write_message(&query(1, "www.slow.example", keepalive))
+            .unwrap();
+        writer
+            .write_message(&query(2, "www.fast.example", Vec::new()))
+            .unwrap();
+
+        // the quick answer overtakes the slow one
+        let mut reader = FramedReader::new(stream.try_clone().unwrap());
+        let first = reader.read_message().unwrap().unwrap();
+        assert_eq!(first.header.id, 2);
+        assert_eq!(first.edns_option(EDNS_TCP_KEEPALIVE), None);
+        let second = reader.read_message().unwrap().unwrap();
+        assert_eq!(second.header.id, 1);
+        assert_eq!(second.answers.len(), 1);
+        assert_eq!(
+            second.edns_option(EDNS_TCP_KEEPALIVE),
+            Some(&100u16.to_be_bytes()[..])
+        );
+
+        // a keepalive option with a timeout in it is only for servers
+        writer
+            .write_message(&query(
+                3,
+                "www.fast.example",
+                vec![(EDNS_TCP_KEEPALIVE, vec![0, 1])],
+            ))
+            .unwrap();
+        let third = reader.read_message().unwrap().unwrap();
+        assert_eq!(third.header.rcode, ResultCode::FORMERR);
+
+        // the server hangs up once the client is done sending
+        stream.shutdown(Shutdown::Write).unwrap();
+        assert!(reader.read_message().unwrap().is_none());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn answers_after_half_close() {
+        let (stream, server) = tcp_server();
+        let mut writer = FramedWriter::new(stream.try_clone().unwrap());
+        writer
+            .write_message(&query(1, "www.slow.example", Vec::new()))
+            .unwrap();
+        writer
+            .write_message(&query(2, "www.slow.example", Vec::new()))
+            .unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+
+        let mut reader = FramedReader::new(stream.try_clone().unwrap());
+        let mut ids = Vec::new();
+        while let Some(response) = reader.read_message().unwrap() {
+            ids.push(response.header.id);
+        }
+        ids.sort();
+        assert_eq!(ids, vec![1, 2]);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn keepalive_over_udp() {
+        let resolver = resolver();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let src = client.local_addr().unwrap();
+
+        let request = query(
+            7,
+            "www.fast.example",
+            vec![(EDNS_TCP_KEEPALIVE, Vec::new())],
+        );
+        handle_request(&resolver, &socket, &Client::Udp(src), request).unwrap();
+
+        let mut buf = vec![0; 1232];
+        let (len, _) = client.recv_from(&mut buf).unwrap();
+        buf.truncate(len);
+        let response = DnsPacket::from_buffer(&mut PacketReader::new(Cursor::new(buf))).unwrap();
+        assert_eq!(response.header.id, 7);
+        assert_eq!(response.header.rcode, ResultCode::FORMERR);
+        assert!(response.answers.is_empty());
+    }
+}
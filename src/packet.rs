@@ -6,6 +6,11 @@ use std::{
 type Error = Box<dyn std::error::Error>;
 type Result<T> = std::result::Result<T, Error>;
 
+/// `Read + Seek` as a single trait, so readers can be type erased
+pub trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek + ?Sized> ReadSeek for T {}
+
 pub struct PacketReader<R> {
     pub read: R,
 }
@@ -50,8 +55,18 @@ impl<R: Read + Seek> PacketReader<R> {
         Ok(u32::from_be_bytes(buf))
     }
 
-    // Read a name
+    // Read a name, in lowercase
     pub fn read_name(&mut self) -> Result<String> {
+        self.read_labels(true)
+    }
+
+    // Read a name as it was sent, for questions that have to echo the
+    // letter case of the query
+    pub fn read_name_preserving_case(&mut self) -> Result<String> {
+        self.read_labels(false)
+    }
+
+    fn read_labels(&mut self, lowercase: bool) -> Result<String> {
         let mut jumped = false;
         let max_jumps = 20;
         let mut jumps_performed = 0;
@@ -89,7 +104,12 @@ impl<R: Read + Seek> PacketReader<R> {
 
                 let mut b = vec![0u8; len as usize];
                 self.read.read_exact(&mut b)?;
-                name_part.push(String::from_utf8_lossy(&b).to_lowercase());
+                let label = String::from_utf8_lossy(&b);
+                name_part.push(if lowercase {
+                    label.to_lowercase()
+                } else {
+                    label.into_owned()
+                });
             }
         }
 
@@ -139,9 +159,9 @@ impl<W: Write> PacketWriter<W> {
 
     pub fn get_name_len(&self, name: impl AsRef<str>) -> usize {
         let mut size = 0;
-        for part in name.as_ref().split('.') {
+        for part in name.as_ref().split('.').filter(|part| !part.is_empty()) {
             size += 1;
-            size += part.as_bytes().len();
+            size += part.len();
         }
         size += 1;
         size
@@ -149,7 +169,9 @@ impl<W: Write> PacketWriter<W> {
 
     pub fn write_name(&mut self, name: impl AsRef<str>) -> Result<usize> {
         let mut size = 0;
-        for part in name.as_ref().split('.') {
+        // empty labels are skipped, so the root "" and names with a
+        // trailing dot encode correctly
+        for part in name.as_ref().split('.').filter(|part| !part.is_empty()) {
             self.write_u8(part.len() as u8)?;
             size += 1;
             size += self.write.write(part.as_bytes())?;
@@ -208,4 +230,28 @@ mod tests {
         pw.write_name(domain_name).unwrap();
         assert_eq!(&vec![5, 98, 97, 105, 100, 117, 3, 99, 111, 109, 0], &v);
     }
+
+    #[test]
+    fn name_case() {
+        let mut v = Vec::new();
+        PacketWriter::new(&mut v)
+            .write_name("wWw.ExAmple.com")
+            .unwrap();
+
+        let mut pr = PacketReader::new(Cursor::new(v));
+        assert_eq!(pr.read_name_preserving_case().unwrap(), "wWw.ExAmple.com");
+        pr.read.set_position(0);
+        assert_eq!(pr.read_name().unwrap(), "www.example.com");
+    }
+
+    #[test]
+    fn write_root_name() {
+        let mut v = Vec::new();
+        let mut pw = PacketWriter {
+            write: Cursor::new(&mut v),
+        };
+        assert_eq!(pw.write_name("").unwrap(), 1);
+        assert_eq!(pw.get_name_len(""), 1);
+        assert_eq!(&vec![0], &v);
+    }
 }
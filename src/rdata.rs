@@ -0,0 +1,602 @@
+//! Record data (RDATA) of resource records.
+//!
+//! Every record type implements the `Rdata` trait, which knows its type code,
+//! its wire encoding and decoding and its presentation format. The built-in
+//! types live here; applications can `register` decoders for their own
+//! (typically private-use, 65280-65534) types, which are then picked up by
+//! `DnsRecord::read` and so by `DnsPacket::from_buffer`.
+use std::{
+    any::Any,
+    collections::HashMap,
+    fmt,
+    hash::{Hash, Hasher},
+    net::{Ipv4Addr, Ipv6Addr},
+    sync::{OnceLock, RwLock},
+};
+
+use crate::dns::QueryType;
+use crate::packet::{PacketReader, PacketWriter, ReadSeek};
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+/// Reader handed to `Rdata::decode`, positioned at the start of the RDATA.
+/// It spans the whole message so compressed names can be followed.
+pub type RdataReader<'a> = PacketReader<&'a mut dyn ReadSeek>;
+
+/// Data of a single resource record.
+pub trait Rdata: RdataBase + fmt::Debug + Send + Sync + 'static {
+    /// numeric RR type code of the type, `rtype` returns it for a value
+    fn type_code() -> u16
+    where
+        Self: Sized;
+
+    /// append the uncompressed wire format, RDLENGTH is derived from it
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<()>;
+
+    /// decode `len` bytes of RDATA
+    fn decode(buffer: &mut RdataReader, len: u16) -> Result<Self>
+    where
+        Self: Sized;
+
+    /// master file representation, without owner, TTL, class and type
+    fn presentation(&self) -> String;
+}
+
+/// Object helpers implemented for every `Rdata + Clone`, so that boxed
+/// record data can be cloned and downcast.
+pub trait RdataBase {
+    /// numeric RR type code
+    fn rtype(&self) -> u16;
+    fn clone_box(&self) -> Box<dyn Rdata>;
+    fn as_any(&self) -> &dyn Any;
+}
+
+impl<T: Rdata + Clone> RdataBase for T {
+    fn rtype(&self) -> u16 {
+        T::type_code()
+    }
+
+    fn clone_box(&self) -> Box<dyn Rdata> {
+        Box::new(self.clone())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl Clone for Box<dyn Rdata> {
+    fn clone(&self) -> Self {
+        (**self).clone_box()
+    }
+}
+
+/// Record data compares by type and wire format, so boxed data of
+/// different concrete types can be compared and hashed.
+impl PartialEq for dyn Rdata {
+    fn eq(&self, other: &Self) -> bool {
+        self.rtype() == other.rtype() && wire(self) == wire(other)
+    }
+}
+
+impl Eq for dyn Rdata {}
+
+impl Hash for dyn Rdata {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.rtype().hash(state);
+        wire(self).hash(state);
+    }
+}
+
+/// wire format of record data, empty if it can't be encoded
+pub fn wire(rdata: &dyn Rdata) -> Vec<u8> {
+    let mut data = Vec::new();
+    if rdata.encode(&mut data).is_err() {
+        data.clear();
+    }
+    data
+}
+
+type DecodeFn = fn(&mut RdataReader, u16) -> Result<Box<dyn Rdata>>;
+
+fn registry() -> &'static RwLock<HashMap<u16, DecodeFn>> {
+    static REGISTRY: OnceLock<RwLock<HashMap<u16, DecodeFn>>> = OnceLock::new();
+    REGISTRY.get_or_init(|| RwLock::new(HashMap::new()))
+}
+
+fn decode_boxed<T: Rdata>(buffer: &mut RdataReader, len: u16) -> Result<Box<dyn Rdata>> {
+    Ok(Box::new(T::decode(buffer, len)?))
+}
+
+/// whether the type code is handled by one of the built-in types
+pub fn is_builtin(rtype: u16) -> bool {
+    !matches!(QueryType::from_num(rtype), QueryType::UNKNOWN(_))
+}
+
+/// Register `T` as the decoder for `rtype`, which has to be the type code
+/// of `T`. Built-in types can't be replaced; registering the same code
+/// again replaces the earlier decoder.
+pub fn register<T: Rdata>(rtype: u16) -> Result<()> {
+    if is_builtin(rtype) {
+        return Err(format!("type {} is built in and can't be registered", rtype).into());
+    }
+    if rtype != T::type_code() {
+        return Err(format!(
+            "type {} can't be registered as type {}",
+            T::type_code(),
+            rtype
+        )
+        .into());
+    }
+    registry()
+        .write()
+        .map_err(|_| "rdata registry poisoned")?
+        .insert(rtype, decode_boxed::<T>);
+    Ok(())
+}
+
+/// Decode RDATA of the given type. Returns `None` for types that are
+/// neither built in nor registered, leaving the reader untouched.
+pub fn decode(rtype: u16, buffer: &mut RdataReader, len: u16) -> Result<Option<Box<dyn Rdata>>> {
+    let qtype = QueryType::from_num(rtype);
+    let decoder: DecodeFn = match builtin_decoder(qtype) {
+        Some(decoder) => decoder,
+        // options aren't record data, `DnsRecord::read` handles them
+        None if qtype == QueryType::OPT => return Ok(None),
+        None => {
+            let registry = registry().read().map_err(|_| "rdata registry poisoned")?;
+            match registry.get(&rtype) {
+                Some(decoder) => *decoder,
+                None => return Ok(None),
+            }
+        }
+    };
+    decoder(buffer, len).map(Some)
+}
+
+/// presentation format of a domain name, which is always fully qualified
+pub fn absolute(name: &str) -> String {
+    format!("{}.", name)
+}
+
+/// quote a character-string for presentation format, octets outside of
+/// printable ASCII are escaped
+pub fn quote(s: &[u8]) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for &b in s {
+        match b {
+            b'"' | b'\\' => {
+                out.push('\\');
+                out.push(b as char);
+            }
+            0x20..=0x7e => out.push(b as char),
+            _ => out.push_str(&format!("\\{:03}", b)),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// serde support for boxed record data: serialized as type code and wire
+/// format, deserialized through the registered decoders
+#[cfg(feature = "serde")]
+pub(crate) mod serde_rdata {
+    use super::{decode, Rdata, RdataReader};
+    use crate::packet::{PacketReader, ReadSeek};
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+    use std::io::Cursor;
+
+    #[derive(Serialize, Deserialize)]
+    struct Wire {
+        rtype: u16,
+        data: Vec<u8>,
+    }
+
+    // serde's `with` hands over a reference to the field itself
+    #[allow(clippy::borrowed_box)]
+    pub fn serialize<S: Serializer>(rdata: &Box<dyn Rdata>, s: S) -> Result<S::Ok, S::Error> {
+        let mut data = Vec::new();
+        rdata.encode(&mut data).map_err(serde::ser::Error::custom)?;
+        Wire {
+            rtype: rdata.rtype(),
+            data,
+        }
+        .serialize(s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Box<dyn Rdata>, D::Error> {
+        let wire = Wire::deserialize(d)?;
+        let len = wire.data.len() as u16;
+        let mut cursor = Cursor::new(wire.data);
+        let mut reader: RdataReader = PacketReader::new(&mut cursor as &mut dyn ReadSeek);
+        decode(wire.rtype, &mut reader, len)
+            .map_err(D::Error::custom)?
+            .ok_or_else(|| D::Error::custom(format!("type {} is not registered", wire.rtype)))
+    }
+}
+
+/// Every built-in type with the fields of its data, each name also being
+/// a `QueryType` variant. `$callback` generates what a type needs from
+/// the list: the structs below, and the variants of `DnsRecord`.
+macro_rules! builtin_types {
+    ($callback:ident) => {
+        $callback! {
+            A { addr: Ipv4Addr },
+            AAAA { addr: Ipv6Addr },
+            NS { host: String },
+            CNAME { host: String },
+            MX { priority: u16, host: String },
+            SOA {
+                m_name: String,
+                r_name: String,
+                serial: u32,
+                refresh: u32,
+                retry: u32,
+                expire: u32,
+                minimum: u32,
+            },
+            TXT {
+                /// the character-strings, arbitrary octets rather than text
+                data: Vec<Vec<u8>>,
+            },
+        }
+    };
+}
+pub(crate) use builtin_types;
+
+/// a struct for each built-in type, and the decoder picking between them
+macro_rules! builtin_structs {
+    ($($name:ident { $($(#[$doc:meta])* $field:ident: $ty:ty),* $(,)? }),* $(,)?) => {
+        $(
+            #[derive(Clone, Debug, PartialEq, Eq)]
+            pub struct $name {
+                $($(#[$doc])* pub $field: $ty,)*
+            }
+        )*
+
+        /// decoder of a built-in type, `None` for OPT and other types
+        fn builtin_decoder(rtype: QueryType) -> Option<DecodeFn> {
+            match rtype {
+                $(QueryType::$name => Some(decode_boxed::<$name>),)*
+                _ => None,
+            }
+        }
+    };
+}
+
+builtin_types!(builtin_structs);
+
+impl Rdata for A {
+    fn type_code() -> u16 {
+        QueryType::A.to_num()
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<()> {
+        buf.extend_from_slice(&self.addr.octets());
+        Ok(())
+    }
+
+    fn decode(buffer: &mut RdataReader, len: u16) -> Result<Self> {
+        if len != 4 {
+            return Err(format!("A record with {} octets of RDATA", len).into());
+        }
+        let addr = Ipv4Addr::from(buffer.read_u32()?);
+        Ok(A { addr })
+    }
+
+    fn presentation(&self) -> String {
+        self.addr.to_string()
+    }
+}
+
+impl Rdata for AAAA {
+    fn type_code() -> u16 {
+        QueryType::AAAA.to_num()
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<()> {
+        buf.extend_from_slice(&self.addr.octets());
+        Ok(())
+    }
+
+    fn decode(buffer: &mut RdataReader, len: u16) -> Result<Self> {
+        if len != 16 {
+            return Err(format!("AAAA record with {} octets of RDATA", len).into());
+        }
+        let mut octets = [0u8; 16];
+        buffer.read.read_exact(&mut octets)?;
+        Ok(AAAA {
+            addr: Ipv6Addr::from(octets),
+        })
+    }
+
+    fn presentation(&self) -> String {
+        self.addr.to_string()
+    }
+}
+
+impl Rdata for NS {
+    fn type_code() -> u16 {
+        QueryType::NS.to_num()
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<()> {
+        PacketWriter::new(buf).write_name(&self.host)?;
+        Ok(())
+    }
+
+    fn decode(buffer: &mut RdataReader, _len: u16) -> Result<Self> {
+        Ok(NS {
+            host: buffer.read_name()?,
+        })
+    }
+
+    fn presentation(&self) -> String {
+        absolute(&self.host)
+    }
+}
+
+impl Rdata for CNAME {
+    fn type_code() -> u16 {
+        QueryType::CNAME.to_num()
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<()> {
+        PacketWriter::new(buf).write_name(&self.host)?;
+        Ok(())
+    }
+
+    fn decode(buffer: &mut RdataReader, _len: u16) -> Result<Self> {
+        Ok(CNAME {
+            host: buffer.read_name()?,
+        })
+    }
+
+    fn presentation(&self) -> String {
+        absolute(&self.host)
+    }
+}
+
+impl Rdata for MX {
+    fn type_code() -> u16 {
+        QueryType::MX.to_num()
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<()> {
+        let mut writer = PacketWriter::new(buf);
+        writer.write_u16(self.priority)?;
+        writer.write_name(&self.host)?;
+        Ok(())
+    }
+
+    fn decode(buffer: &mut RdataReader, _len: u16) -> Result<Self> {
+        let priority = buffer.read_u16()?;
+        let host = buffer.read_name()?;
+        Ok(MX { priority, host })
+    }
+
+    fn presentation(&self) -> String {
+        format!("{} {}", self.priority, absolute(&self.host))
+    }
+}
+
+impl Rdata for SOA {
+    fn type_code() -> u16 {
+        QueryType::SOA.to_num()
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<()> {
+        let mut writer = PacketWriter::new(buf);
+        writer.write_name(&self.m_name)?;
+        writer.write_name(&self.r_name)?;
+        writer.write_u32(self.serial)?;
+        writer.write_u32(self.refresh)?;
+        writer.write_u32(self.retry)?;
+        writer.write_u32(self.expire)?;
+        writer.write_u32(self.minimum)?;
+        Ok(())
+    }
+
+    fn decode(buffer: &mut RdataReader, _len: u16) -> Result<Self> {
+        Ok(SOA {
+            m_name: buffer.read_name()?,
+            r_name: buffer.read_name()?,
+            serial: buffer.read_u32()?,
+            refresh: buffer.read_u32()?,
+            retry: buffer.read_u32()?,
+            expire: buffer.read_u32()?,
+            minimum: buffer.read_u32()?,
+        })
+    }
+
+    fn presentation(&self) -> String {
+        format!(
+            "{} {} {} {} {} {} {}",
+            absolute(&self.m_name),
+            absolute(&self.r_name),
+            self.serial,
+            self.refresh,
+            self.retry,
+            self.expire,
+            self.minimum
+        )
+    }
+}
+
+impl Rdata for TXT {
+    fn type_code() -> u16 {
+        QueryType::TXT.to_num()
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<()> {
+        for s in &self.data {
+            if s.len() > 255 {
+                return Err("TXT character-string longer than 255 octets".into());
+            }
+            buf.push(s.len() as u8);
+            buf.extend_from_slice(s);
+        }
+        Ok(())
+    }
+
+    fn decode(buffer: &mut RdataReader, len: u16) -> Result<Self> {
+        // one or more <character-string>s filling the whole RDATA
+        let mut data = Vec::new();
+        let mut remaining = len as usize;
+        while remaining > 0 {
+            let len = buffer.read_u8()? as usize;
+            if len + 1 > remaining {
+                return Err("TXT character-string exceeds RDLENGTH".into());
+            }
+            let mut b = vec![0u8; len];
+            buffer.read.read_exact(&mut b)?;
+            data.push(b);
+            remaining -= len + 1;
+        }
+        Ok(TXT { data })
+    }
+
+    fn presentation(&self) -> String {
+        self.data
+            .iter()
+            .map(|s| quote(s))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{register, Rdata, RdataReader};
+    use crate::dns::{DnsPacket, DnsRecord};
+    use crate::packet::{PacketReader, PacketWriter};
+    use std::io::Cursor;
+
+    type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+    /// a private-use type carrying an opaque token
+    #[derive(Clone, Debug, PartialEq)]
+    struct Token(Vec<u8>);
+
+    impl Rdata for Token {
+        fn type_code() -> u16 {
+            65300
+        }
+
+        fn encode(&self, buf: &mut Vec<u8>) -> Result<()> {
+            buf.extend_from_slice(&self.0);
+            Ok(())
+        }
+
+        fn decode(buffer: &mut RdataReader, len: u16) -> Result<Self> {
+            let mut data = vec![0u8; len as usize];
+            buffer.read.read_exact(&mut data)?;
+            Ok(Token(data))
+        }
+
+        fn presentation(&self) -> String {
+            format!("\\# {} {:02x?}", self.0.len(), self.0)
+        }
+    }
+
+    #[test]
+    fn builtin_types_cannot_be_registered() {
+        assert!(register::<Token>(1).is_err());
+    }
+
+    #[test]
+    fn registered_type_code_must_match() {
+        assert!(register::<Token>(65301).is_err());
+    }
+
+    #[test]
+    fn custom_type_round_trip() {
+        register::<Token>(65300).unwrap();
+
+        let mut packet = DnsPacket::new();
+        packet.answers.push(DnsRecord::CUSTOM {
+            domain: "example.com".into(),
+            rdata: Box::new(Token(vec![1, 2, 3])),
+            ttl: 60,
+        });
+        packet.answers.push(DnsRecord::MX {
+            domain: "example.com".into(),
+            priority: 10,
+            host: "mail.example.com".into(),
+            ttl: 60,
+        });
+
+        let mut buf = Vec::new();
+        packet.write(&mut PacketWriter::new(&mut buf)).unwrap();
+        let parsed = DnsPacket::from_buffer(&mut PacketReader::new(Cursor::new(buf))).unwrap();
+
+        match parsed.answers[0] {
+            DnsRecord::CUSTOM { ref rdata, ttl, .. } => {
+                assert_eq!(ttl, 60);
+                assert_eq!(
+                    rdata.as_any().downcast_ref::<Token>(),
+                    Some(&Token(vec![1, 2, 3]))
+                );
+            }
+            ref r => panic!("unexpected record {:?}", r),
+        }
+        assert!(
+            matches!(parsed.answers[1], DnsRecord::MX { priority: 10, ref host, .. }
+            if host == "mail.example.com")
+        );
+    }
+
+    #[test]
+    fn binary_txt() {
+        let mut packet = DnsPacket::new();
+        packet.answers.push(DnsRecord::TXT {
+            domain: "example.com".into(),
+            data: vec![vec![0xff, 0x00, b'a'], b"\"quoted\"".to_vec()],
+            ttl: 60,
+        });
+
+        let mut buf = Vec::new();
+        packet.write(&mut PacketWriter::new(&mut buf)).unwrap();
+        let parsed = DnsPacket::from_buffer(&mut PacketReader::new(Cursor::new(buf))).unwrap();
+
+        // octets come back as they were, not replaced as invalid UTF-8
+        assert_eq!(parsed.answers, packet.answers);
+        assert_eq!(
+            parsed.answers[0].rdata().unwrap().presentation(),
+            "\"\\255\\000a\" \"\\\"quoted\\\"\""
+        );
+    }
+
+    #[test]
+    fn unknown_type_round_trip() {
+        let mut packet = DnsPacket::new();
+        packet.answers.push(DnsRecord::UNKNOWN {
+            domain: "example.com".into(),
+            qtype: 65280,
+            data: vec![1, 2, 3, 4, 5],
+            ttl: 60,
+        });
+        packet.header.an_count = 1;
+
+        let mut buf = Vec::new();
+        packet.write(&mut PacketWriter::new(&mut buf)).unwrap();
+        let parsed = DnsPacket::from_buffer(&mut PacketReader::new(Cursor::new(buf))).unwrap();
+        assert_eq!(parsed.answers, packet.answers);
+    }
+
+    #[test]
+    fn address_length() {
+        // an A record with RDLENGTH 5 followed by a stray octet
+        let mut buf = vec![0, 1, 0x81, 0x80, 0, 0, 0, 1, 0, 0, 0, 0];
+        buf.extend_from_slice(b"\x07example\x03com\x00");
+        buf.extend_from_slice(&[0, 1, 0, 1, 0, 0, 0, 60, 0, 5, 192, 0, 2, 1, 9]);
+        assert!(DnsPacket::from_buffer(&mut PacketReader::new(Cursor::new(buf))).is_err());
+
+        let mut buf = vec![0, 1, 0x81, 0x80, 0, 0, 0, 1, 0, 0, 0, 0];
+        buf.extend_from_slice(b"\x07example\x03com\x00");
+        buf.extend_from_slice(&[0, 28, 0, 1, 0, 0, 0, 60, 0, 4, 192, 0, 2, 1]);
+        assert!(DnsPacket::from_buffer(&mut PacketReader::new(Cursor::new(buf))).is_err());
+    }
+}
@@ -0,0 +1,1772 @@
+//! Iterative resolution starting from the root servers.
+use std::collections::HashSet;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread::Builder;
+use std::time::{Duration, Instant};
+
+use rand::{seq::SliceRandom, thread_rng, Rng};
+
+use crate::cache::{Cache, CacheConfig};
+use crate::dns::{is_subdomain, DnsPacket, DnsRecord, QueryType, ResultCode};
+use crate::infra::{InfraCache, InfraConfig};
+use crate::pool::{PoolConfig, PooledTransport};
+use crate::roothints::RootHints;
+use crate::transport::Transport;
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+/// Settings of a `Resolver`.
+#[derive(Clone, Debug)]
+pub struct ResolverConfig {
+    /// servers every resolution starts from
+    pub root_hints: RootHints,
+    /// port queries are sent to
+    pub port: u16,
+    /// how long to wait for a response to the first try of a query
+    pub timeout: Duration,
+    /// how many more times all servers are tried, with the timeout
+    /// doubling each time
+    pub retries: usize,
+    /// upper bound for the doubled timeout
+    pub max_timeout: Duration,
+    /// how name servers are picked and when they are given up on
+    pub infra: InfraConfig,
+    /// IP versions name servers are contacted over
+    pub address_family: AddressFamily,
+    /// how deep name server names may be resolved recursively
+    pub max_depth: usize,
+    /// how many CNAMEs an answer may chain through
+    pub max_cname_chain: usize,
+    /// how many queries a single resolution may send upstream
+    pub max_queries: usize,
+    /// how long a single resolution may take overall
+    pub resolution_timeout: Duration,
+    /// TTL clamps and entry limits of the answer and delegation caches
+    pub cache: CacheConfig,
+    /// sockets and connections kept for talking to name servers
+    pub pool: PoolConfig,
+    /// randomize the letter case of query names and only accept responses
+    /// that echo it exactly (draft-vixie-dnsext-dns0x20)
+    pub case_randomization: bool,
+    /// only show name servers as much of the query name as they need to
+    /// refer onwards (RFC 9156)
+    pub qname_minimisation: bool,
+}
+
+impl Default for ResolverConfig {
+    fn default() -> Self {
+        ResolverConfig {
+            root_hints: RootHints::builtin(),
+            port: 53,
+            timeout: Duration::from_secs(1),
+            retries: 2,
+            max_timeout: Duration::from_secs(4),
+            infra: InfraConfig::default(),
+            address_family: AddressFamily::default(),
+            max_depth: 8,
+            max_cname_chain: 8,
+            max_queries: 64,
+            resolution_timeout: Duration::from_secs(10),
+            cache: CacheConfig::default(),
+            pool: PoolConfig::default(),
+            case_randomization: false,
+            qname_minimisation: false,
+        }
+    }
+}
+
+/// Which IP versions name servers are contacted over.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AddressFamily {
+    V4Only,
+    V6Only,
+    /// both, IPv4 addresses first
+    #[default]
+    PreferV4,
+    /// both, IPv6 addresses first
+    PreferV6,
+}
+
+impl AddressFamily {
+    /// whether name servers at `addr` may be contacted
+    pub fn allows(self, addr: &IpAddr) -> bool {
+        match self {
+            AddressFamily::V4Only => addr.is_ipv4(),
+            AddressFamily::V6Only => addr.is_ipv6(),
+            AddressFamily::PreferV4 | AddressFamily::PreferV6 => true,
+        }
+    }
+
+    /// the address record types to look up for a name server, preferred
+    /// first
+    fn qtypes(self) -> &'static [QueryType] {
+        match self {
+            AddressFamily::V4Only => &[QueryType::A],
+            AddressFamily::V6Only => &[QueryType::AAAA],
+            AddressFamily::PreferV4 => &[QueryType::A, QueryType::AAAA],
+            AddressFamily::PreferV6 => &[QueryType::AAAA, QueryType::A],
+        }
+    }
+
+    /// move addresses of the preferred family to the front, keeping the
+    /// order otherwise
+    fn sort(self, addrs: &mut [IpAddr]) {
+        match self {
+            AddressFamily::PreferV4 => addrs.sort_by_key(|a| a.is_ipv6()),
+            AddressFamily::PreferV6 => addrs.sort_by_key(|a| a.is_ipv4()),
+            AddressFamily::V4Only | AddressFamily::V6Only => {}
+        }
+    }
+}
+
+/// Outcome of a resolution: the response code and the sections of the
+/// final response.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Resolution {
+    pub rcode: ResultCode,
+    pub answers: Vec<DnsRecord>,
+    pub authorities: Vec<DnsRecord>,
+    pub additionals: Vec<DnsRecord>,
+}
+
+impl From<DnsPacket> for Resolution {
+    fn from(packet: DnsPacket) -> Resolution {
+        Resolution {
+            rcode: packet.header.rcode,
+            answers: packet.answers,
+            authorities: packet.authorities,
+            additionals: packet.resources,
+        }
+    }
+}
+
+/// A resolution ran out of depth, queries or time, or went in circles.
+/// `resolve` answers these with SERVFAIL.
+#[derive(Debug)]
+pub struct LimitExceeded(String);
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "resolution aborted: {}", self.0)
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+/// State of a single resolution, shared by the lookups of name server
+/// addresses it needs on the way, so that together they stay within the
+/// configured limits.
+#[derive(Debug)]
+struct ResolutionContext {
+    depth: usize,
+    /// questions already sent to each server
+    visited: HashSet<(String, QueryType, IpAddr)>,
+    queries: usize,
+    deadline: Instant,
+}
+
+impl ResolutionContext {
+    fn new(config: &ResolverConfig) -> ResolutionContext {
+        ResolutionContext {
+            depth: 0,
+            visited: HashSet::new(),
+            queries: 0,
+            deadline: Instant::now() + config.resolution_timeout,
+        }
+    }
+
+    /// account for a query about to be sent, failing if the resolution is
+    /// out of time or budget
+    fn spend(&mut self, config: &ResolverConfig) -> Result<()> {
+        if Instant::now() >= self.deadline {
+            return Err(
+                LimitExceeded(format!("no answer within {:?}", config.resolution_timeout)).into(),
+            );
+        }
+        if self.queries >= config.max_queries {
+            return Err(LimitExceeded(format!("sent {} queries", config.max_queries)).into());
+        }
+        self.queries += 1;
+        Ok(())
+    }
+}
+
+/// how long to wait before priming again after it failed
+const PRIMING_RETRY: Duration = Duration::from_secs(60);
+
+/// shortest time primed root servers are used for, so that a tiny TTL
+/// doesn't send a priming query for every resolution
+const MIN_PRIMING_TTL: u32 = 60;
+
+/// Root servers learned by priming, used until the root NS RRset expires.
+#[derive(Clone, Debug)]
+struct PrimedRoots {
+    hints: RootHints,
+    expires: Instant,
+}
+
+/// minimised queries a resolution sends before asking for the whole name,
+/// so that names with many labels don't use up the query budget
+/// (MAX_MINIMISE_COUNT of RFC 9156)
+const MAX_MINIMISE_COUNT: usize = 10;
+
+/// Recursive resolver that walks the delegation chain from the root.
+#[derive(Clone)]
+pub struct Resolver {
+    config: ResolverConfig,
+    transport: Arc<dyn Transport>,
+    roots: Arc<RwLock<Option<PrimedRoots>>>,
+    /// set while the root servers are primed again in the background
+    repriming: Arc<AtomicBool>,
+    cache: Arc<Cache>,
+    /// NS sets of zone cuts and the addresses of their name servers
+    delegations: Arc<Cache>,
+    infra: Arc<InfraCache>,
+}
+
+impl Default for Resolver {
+    fn default() -> Self {
+        Resolver::new(ResolverConfig::default())
+    }
+}
+
+impl Resolver {
+    /// resolver that talks to name servers over pooled UDP sockets and
+    /// TCP connections
+    pub fn new(config: ResolverConfig) -> Resolver {
+        let transport = Arc::new(PooledTransport::new(config.pool.clone()));
+        Resolver::with_transport(config, transport)
+    }
+
+    /// resolver that sends every query through `transport`
+    pub fn with_transport(config: ResolverConfig, transport: Arc<dyn Transport>) -> Resolver {
+        Resolver {
+            transport,
+            roots: Arc::new(RwLock::new(None)),
+            repriming: Arc::new(AtomicBool::new(false)),
+            cache: Arc::new(Cache::new(config.cache.clone())),
+            delegations: Arc::new(Cache::new(config.cache.clone())),
+            infra: Arc::new(InfraCache::new(config.infra.clone())),
+            config,
+        }
+    }
+
+    pub fn config(&self) -> &ResolverConfig {
+        &self.config
+    }
+
+    /// the answer cache, shared by clones of this resolver
+    pub fn cache(&self) -> &Cache {
+        &self.cache
+    }
+
+    /// the zone cuts learned from referrals, shared by clones of this
+    /// resolver
+    pub fn delegations(&self) -> &Cache {
+        &self.delegations
+    }
+
+    /// round trip times and failures of the name servers queried so far
+    pub fn infra(&self) -> &InfraCache {
+        &self.infra
+    }
+
+    /// Send a priming query (RFC 8109) for the root NS RRset to the
+    /// configured hints. On success the learned servers and their addresses
+    /// replace the hints until the RRset's TTL runs out, within the TTL
+    /// limits of the cache and for at least a minute. After that the next
+    /// resolution primes again in the background, still using the expired
+    /// set meanwhile. A failed priming is retried a minute later, the
+    /// hints are used until then.
+    pub fn prime(&self) -> Result<RootHints> {
+        let primed = self.priming_query();
+        let (hints, ttl) = match &primed {
+            Ok((hints, ttl)) => {
+                let ttl = (*ttl)
+                    .clamp(self.config.cache.min_ttl, self.config.cache.max_ttl)
+                    .max(MIN_PRIMING_TTL);
+                (hints.clone(), Duration::from_secs(ttl as u64))
+            }
+            Err(_) => (self.config.root_hints.clone(), PRIMING_RETRY),
+        };
+        *self.roots.write().unwrap() = Some(PrimedRoots {
+            hints,
+            expires: Instant::now() + ttl,
+        });
+        primed.map(|(hints, _)| hints)
+    }
+
+    /// the learned root servers and the TTL of the root NS RRset
+    fn priming_query(&self) -> Result<(RootHints, u32)> {
+        let mut hints = self.config.root_hints.addrs();
+        hints.shuffle(&mut thread_rng());
+
+        let mut ctx = ResolutionContext::new(&self.config);
+        let response = self.query_servers(&mut ctx, "", QueryType::NS, &hints)?;
+        if response.header.rcode != ResultCode::NOERROR {
+            return Err(format!("priming query failed with {:?}", response.header.rcode).into());
+        }
+
+        let ttl = response
+            .answers
+            .iter()
+            .filter(|r| matches!(r, DnsRecord::NS { .. }) && r.domain().is_empty())
+            .map(|r| r.ttl())
+            .min()
+            .ok_or("priming response has no root NS records")?;
+
+        let records: Vec<DnsRecord> = response
+            .answers
+            .into_iter()
+            .chain(response.resources)
+            .collect();
+        let mut primed = RootHints::from_records(&records);
+
+        // servers the response carried no addresses for keep the ones from
+        // the hints, if they are listed there
+        for server in primed.servers.iter_mut().filter(|s| s.addrs.is_empty()) {
+            if let Some(hint) = self
+                .config
+                .root_hints
+                .servers
+                .iter()
+                .find(|h| h.name == server.name)
+            {
+                server.addrs = hint.addrs.clone();
+            }
+        }
+        primed.servers.retain(|s| !s.addrs.is_empty());
+        if primed.servers.is_empty() {
+            return Err("priming response has no root server addresses".into());
+        }
+
+        Ok((primed, ttl))
+    }
+
+    /// the root servers currently in use: the primed set while it is
+    /// fresh, the configured hints otherwise
+    pub fn root_hints(&self) -> RootHints {
+        match &*self.roots.read().unwrap() {
+            Some(primed) if primed.expires > Instant::now() => primed.hints.clone(),
+            _ => self.config.root_hints.clone(),
+        }
+    }
+
+    /// Recursive lookup name, answered from the cache when possible. A
+    /// resolution that exceeds the configured limits ends in SERVFAIL.
+    pub fn resolve(&self, qname: &str, qtype: QueryType) -> Result<Resolution> {
+        if let Some(cached) = self.cache.lookup(qname, qtype, self.config.max_cname_chain) {
+            log::debug!("cache hit for {:?} {}", qtype, qname);
+            return Ok(cached);
+        }
+
+        let mut ctx = ResolutionContext::new(&self.config);
+        let response = match self.recursive_lookup(&mut ctx, qname, qtype) {
+            Ok(response) => response,
+            Err(e) if e.is::<LimitExceeded>() => {
+                log::info!("lookup of {:?} {} failed: {}", qtype, qname, e);
+                return Ok(Resolution {
+                    rcode: ResultCode::SERVFAIL,
+                    answers: Vec::new(),
+                    authorities: Vec::new(),
+                    additionals: Vec::new(),
+                });
+            }
+            Err(e) => return Err(e),
+        };
+        self.cache.insert_records(&response.answers);
+
+        // a negative answer is about the end of the CNAME chain
+        let target = response
+            .answers
+            .iter()
+            .fold(qname, |name, r| match r {
+                DnsRecord::CNAME { domain, host, .. } if domain.eq_ignore_ascii_case(name) => host,
+                _ => name,
+            })
+            .to_owned();
+        if !response.has_answer(&target, qtype) {
+            self.cache.insert_negative(
+                &target,
+                qtype,
+                response.header.rcode,
+                &response.authorities,
+            );
+        }
+        Ok(Resolution::from(response))
+    }
+
+    fn recursive_lookup(
+        &self,
+        ctx: &mut ResolutionContext,
+        qname: &str,
+        qtype: QueryType,
+    ) -> Result<DnsPacket> {
+        if ctx.depth > self.config.max_depth {
+            return Err(LimitExceeded(format!(
+                "name server lookups nested deeper than {}",
+                self.config.max_depth
+            ))
+            .into());
+        }
+
+        // starting with the closest known zone cut, `zone` being the zone
+        // the servers are asked about, which their responses may not
+        // stray from
+        let (mut zone, mut servers) = self.closest_servers(qname);
+
+        let mut name = qname.to_owned();
+        // the CNAMEs leading from `qname` to `name`
+        let mut chain: Vec<DnsRecord> = Vec::new();
+
+        // name servers of the current zone cut whose addresses aren't
+        // known yet, tried when the known ones fail
+        let mut pending: Vec<String> = Vec::new();
+
+        // with QNAME minimisation, how many labels of `name` the servers
+        // of `zone` get to see: one more than the zone has
+        let mut minimise = self.config.qname_minimisation;
+        let mut revealed = label_count(&zone) + 1;
+        let mut minimised_queries = 0;
+
+        let mut response = loop {
+            // ask for A of the name one label below the zone, and for the
+            // whole name once no zone cut can be left in between (RFC 9156)
+            let hidden =
+                minimise && revealed < label_count(&name) && minimised_queries < MAX_MINIMISE_COUNT;
+            let (asked, asked_type) = if hidden {
+                (last_labels(&name, revealed), QueryType::A)
+            } else {
+                (name.clone(), qtype)
+            };
+
+            let mut response = match self.query_servers(ctx, &asked, asked_type, &servers) {
+                Ok(response) => response,
+                // servers that choke on minimised queries get the whole name
+                Err(e) if hidden && !e.is::<LimitExceeded>() => {
+                    log::debug!(
+                        "minimised query for {} failed, asking for {}: {}",
+                        asked,
+                        name,
+                        e
+                    );
+                    minimise = false;
+                    continue;
+                }
+                Err(e) if !pending.is_empty() && !e.is::<LimitExceeded>() => {
+                    servers = self.resolve_ns(ctx, &mut pending)?;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            response.retain_in_bailiwick(&zone);
+            self.cache_referral(&asked, &response);
+
+            if hidden {
+                minimised_queries += 1;
+                // broken servers answer NXDOMAIN or fail for empty
+                // non-terminals, so only the whole name can tell
+                if response.header.rcode != ResultCode::NOERROR {
+                    log::debug!(
+                        "minimised query for {} got {:?}, asking for {}",
+                        asked,
+                        response.header.rcode,
+                        name
+                    );
+                    minimise = false;
+                    continue;
+                }
+                // no zone cut at `asked`, reveal the next label
+                let delegated = response.answers.is_empty()
+                    && response
+                        .get_ns(&asked)
+                        .any(|(owner, _)| owner.len() > zone.len());
+                if !delegated {
+                    revealed += 1;
+                    continue;
+                }
+            } else {
+                // follow the aliases the response carries, an NXDOMAIN then
+                // refers to the last target (RFC 6604)
+                let rcode = response.header.rcode;
+                let aliased = qtype != QueryType::CNAME
+                    && matches!(rcode, ResultCode::NOERROR | ResultCode::NXDOMAIN)
+                    && self.follow_cnames(&response, &mut name, &mut chain)?;
+
+                if rcode == ResultCode::NOERROR {
+                    // the question is answered
+                    if response.has_answer(&name, qtype) {
+                        break response;
+                    }
+                    // continue with the target of the alias
+                    if aliased {
+                        (zone, servers) = self.closest_servers(&name);
+                        revealed = label_count(&zone) + 1;
+                        pending.clear();
+                        continue;
+                    }
+                    // the name exists, but has no records of this type
+                    if response.is_nodata() {
+                        break response;
+                    }
+                    // an authoritative answer that doesn't fit the question, a
+                    // referral can't be expected from here
+                    if response.header.aa {
+                        break response;
+                    }
+                }
+
+                // the authoritative name servers telling us that the name doesn't exist.
+                if rcode == ResultCode::NXDOMAIN {
+                    break response;
+                }
+            }
+
+            // a referral: continue with the name servers of the closest
+            // cut that came with glue, or resolve the addresses of the
+            // others first. Glue outside the zone was dropped, so those
+            // name servers are resolved like the ones without glue.
+            let Some(cut) = response
+                .get_ns(&asked)
+                .map(|(owner, _)| owner)
+                .max_by_key(|owner| owner.len())
+                .map(str::to_owned)
+            else {
+                break response;
+            };
+            let hosts: Vec<&str> = response
+                .get_ns(&asked)
+                .filter(|(owner, _)| owner.eq_ignore_ascii_case(&cut))
+                .map(|(_, host)| host)
+                .collect();
+            let (glue, unresolved): (Vec<&str>, Vec<&str>) = hosts.into_iter().partition(|host| {
+                response.resources.iter().any(|r| {
+                    r.domain().eq_ignore_ascii_case(host) && self.usable_address(r).is_some()
+                })
+            });
+            pending = unresolved.into_iter().map(str::to_owned).collect();
+            servers = response
+                .resources
+                .iter()
+                .filter(|r| {
+                    glue.iter()
+                        .any(|host| r.domain().eq_ignore_ascii_case(host))
+                })
+                .filter_map(|r| self.usable_address(r))
+                .collect();
+            zone = cut;
+            revealed = label_count(&zone) + 1;
+            if servers.is_empty() {
+                servers = self.resolve_ns(ctx, &mut pending)?;
+            }
+        };
+
+        // the answer starts with the whole chain of aliases
+        response.answers.retain(|r| !chain.contains(r));
+        chain.append(&mut response.answers);
+        response.answers = chain;
+        Ok(response)
+    }
+
+    /// Resolve the addresses of the name servers in `pending` in turn, until
+    /// one of them has any
+    fn resolve_ns(
+        &self,
+        ctx: &mut ResolutionContext,
+        pending: &mut Vec<String>,
+    ) -> Result<Vec<IpAddr>> {
+        while !pending.is_empty() {
+            let host = pending.remove(0);
+
+            // the address types of the preferred family first, the other
+            // one only when that gives nothing
+            for qtype in self.config.address_family.qtypes() {
+                ctx.depth += 1;
+                let response = self.recursive_lookup(ctx, &host, *qtype);
+                ctx.depth -= 1;
+
+                let response = match response {
+                    Ok(response) => response,
+                    Err(e) if e.is::<LimitExceeded>() => return Err(e),
+                    Err(e) => {
+                        log::debug!(
+                            "failed to resolve {:?} of name server {}: {}",
+                            qtype,
+                            host,
+                            e
+                        );
+                        continue;
+                    }
+                };
+
+                let records: Vec<DnsRecord> = response
+                    .answers
+                    .into_iter()
+                    .filter(|r| self.usable_address(r).is_some())
+                    .collect();
+                let owned: Vec<DnsRecord> = records
+                    .iter()
+                    .filter(|r| r.domain().eq_ignore_ascii_case(&host))
+                    .cloned()
+                    .collect();
+                self.delegations.insert_records(&owned);
+
+                let addrs: Vec<IpAddr> = records
+                    .iter()
+                    .filter_map(|r| self.usable_address(r))
+                    .collect();
+                if !addrs.is_empty() {
+                    return Ok(addrs);
+                }
+            }
+        }
+        Err("no name server with a known address left".into())
+    }
+
+    /// the address of an A or AAAA record, if the address family policy
+    /// allows contacting it
+    fn usable_address(&self, record: &DnsRecord) -> Option<IpAddr> {
+        address(record).filter(|addr| self.config.address_family.allows(addr))
+    }
+
+    /// Move `name` along the CNAMEs in the answer section, collecting them
+    /// in `chain`. Returns whether `name` changed. Fails on loops and on
+    /// chains longer than `max_cname_chain`.
+    fn follow_cnames(
+        &self,
+        response: &DnsPacket,
+        name: &mut String,
+        chain: &mut Vec<DnsRecord>,
+    ) -> Result<bool> {
+        let mut aliased = false;
+        while let Some(record) = response
+            .answers
+            .iter()
+            .find(|r| matches!(r, DnsRecord::CNAME { .. }) && r.domain().eq_ignore_ascii_case(name))
+        {
+            let DnsRecord::CNAME { host, .. } = record else {
+                unreachable!();
+            };
+            if chain.len() >= self.config.max_cname_chain {
+                return Err(format!(
+                    "CNAME chain longer than {} records",
+                    self.config.max_cname_chain
+                )
+                .into());
+            }
+            chain.push(record.clone());
+            if chain.iter().any(|r| r.domain().eq_ignore_ascii_case(host)) {
+                return Err(format!("CNAME loop at {}", host).into());
+            }
+            *name = host.clone();
+            aliased = true;
+        }
+        Ok(aliased)
+    }
+
+    /// remember the zone cut a referral points to, with its glue
+    fn cache_referral(&self, qname: &str, response: &DnsPacket) {
+        if !response.answers.is_empty() {
+            return;
+        }
+        let ns: Vec<DnsRecord> = response
+            .authorities
+            .iter()
+            .filter(|r| matches!(r, DnsRecord::NS { .. }) && is_subdomain(qname, r.domain()))
+            .cloned()
+            .collect();
+        let glue: Vec<DnsRecord> = response
+            .resources
+            .iter()
+            .filter(|r| {
+                address(r).is_some()
+                    && ns.iter().any(|n| {
+                        matches!(n, DnsRecord::NS { host, .. } if host.eq_ignore_ascii_case(r.domain()))
+                    })
+            })
+            .cloned()
+            .collect();
+        self.delegations.insert_records(&ns);
+        self.delegations.insert_records(&glue);
+    }
+
+    /// The closest zone cut above `qname` whose servers have known
+    /// addresses, with those addresses in random order. The root and its
+    /// servers if no such cut is cached.
+    fn closest_servers(&self, qname: &str) -> (String, Vec<IpAddr>) {
+        let mut zone = qname;
+        while !zone.is_empty() {
+            if let Some(ns) = self.delegations.get(zone, QueryType::NS) {
+                let mut addrs: Vec<IpAddr> = ns
+                    .records()
+                    .iter()
+                    .filter_map(|r| match r {
+                        DnsRecord::NS { host, .. } => Some(host.clone()),
+                        _ => None,
+                    })
+                    .flat_map(|host| {
+                        [QueryType::A, QueryType::AAAA]
+                            .into_iter()
+                            .filter_map(move |t| self.delegations.get(&host, t))
+                    })
+                    .flat_map(|set| set.records())
+                    .filter_map(|r| self.usable_address(&r))
+                    .collect();
+                if !addrs.is_empty() {
+                    log::debug!("starting {} at cached delegation {}", qname, zone);
+                    addrs.shuffle(&mut thread_rng());
+                    return (zone.to_owned(), addrs);
+                }
+            }
+            zone = zone.split_once('.').map_or("", |(_, parent)| parent);
+        }
+        (String::new(), self.root_servers())
+    }
+
+    /// all root server addresses in random order, so load is spread and a
+    /// single unreachable root doesn't stall every resolution
+    fn root_servers(&self) -> Vec<IpAddr> {
+        let (hints, expired) = match &*self.roots.read().unwrap() {
+            Some(primed) => (primed.hints.clone(), primed.expires <= Instant::now()),
+            None => (self.config.root_hints.clone(), false),
+        };
+        if expired {
+            self.reprime();
+        }
+
+        let mut addrs = hints.addrs();
+        addrs.shuffle(&mut thread_rng());
+        addrs
+    }
+
+    /// prime again on a thread of its own, unless that already happens
+    fn reprime(&self) {
+        if self.repriming.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let resolver = self.clone();
+        let spawned = Builder::new().name("root-priming".into()).spawn(move || {
+            if let Err(e) = resolver.prime() {
+                log::warn!("re-priming root servers failed, using root hints: {}", e);
+            }
+            resolver.repriming.store(false, Ordering::SeqCst);
+        });
+        if spawned.is_err() {
+            self.repriming.store(false, Ordering::SeqCst);
+        }
+    }
+
+    /// Query the servers in turn, fastest first and the preferred address
+    /// family before the other, until one of them responds, going over all
+    /// of them again with a doubled timeout up to `retries` times. Servers
+    /// marked down are skipped unless all of them are, servers
+    /// that fail every try are marked down. Servers that were already
+    /// asked the same question during this resolution are skipped, asking
+    /// them again means the delegations go in circles. A SERVFAIL, REFUSED
+    /// or FORMERR counts as a failed try and the next server is asked,
+    /// such a response is only returned when no server did better.
+    fn query_servers(
+        &self,
+        ctx: &mut ResolutionContext,
+        qname: &str,
+        qtype: QueryType,
+        servers: &[IpAddr],
+    ) -> Result<DnsPacket> {
+        let family = self.config.address_family;
+        let servers: Vec<IpAddr> = servers
+            .iter()
+            .copied()
+            .filter(|ns| family.allows(ns))
+            .collect();
+        if servers.is_empty() {
+            return Err(format!("no name servers to query over {:?}", family).into());
+        }
+
+        let key = qname.to_ascii_lowercase();
+        let fresh: Vec<IpAddr> = servers
+            .into_iter()
+            .filter(|ns| ctx.visited.insert((key.clone(), qtype, *ns)))
+            .collect();
+        if fresh.is_empty() {
+            return Err(LimitExceeded(format!(
+                "asked every server for {:?} {} already",
+                qtype, qname
+            ))
+            .into());
+        }
+
+        let (up, down): (Vec<IpAddr>, Vec<IpAddr>) =
+            fresh.into_iter().partition(|ns| !self.infra.is_down(*ns));
+        let mut candidates = self.infra.order(if up.is_empty() { &down } else { &up });
+        family.sort(&mut candidates);
+
+        let mut last_err = None;
+        // servers that responded with an error, asking again won't help
+        let mut failed: Vec<(IpAddr, DnsPacket)> = Vec::new();
+        let mut timeout = self.config.timeout;
+        for _ in 0..=self.config.retries {
+            for ns in &candidates {
+                if failed.iter().any(|(server, _)| server == ns) {
+                    continue;
+                }
+                ctx.spend(&self.config)?;
+                log::debug!("attempting lookup of {:?} {} with ns {}", qtype, qname, ns);
+
+                let remaining = ctx.deadline.saturating_duration_since(Instant::now());
+                let timeout = timeout.min(remaining).max(Duration::from_millis(1));
+                let sent = Instant::now();
+                match self.query(
+                    qname,
+                    qtype,
+                    SocketAddr::new(*ns, self.config.port),
+                    timeout,
+                    ctx,
+                ) {
+                    Ok(response)
+                        if matches!(
+                            response.header.rcode,
+                            ResultCode::SERVFAIL | ResultCode::REFUSED | ResultCode::FORMERR
+                        ) =>
+                    {
+                        log::debug!(
+                            "lookup of {} with ns {} failed with {:?}",
+                            qname,
+                            ns,
+                            response.header.rcode
+                        );
+                        self.infra.failure(*ns, sent.elapsed());
+                        failed.push((*ns, response));
+                    }
+                    Ok(response) => {
+                        self.infra.success(*ns, sent.elapsed());
+                        return Ok(response);
+                    }
+                    Err(e) => {
+                        log::debug!("lookup of {} with ns {} failed: {}", qname, ns, e);
+                        self.infra.failure(*ns, sent.elapsed());
+                        last_err = Some(e);
+                    }
+                }
+            }
+            timeout = (timeout * 2).min(self.config.max_timeout);
+        }
+
+        for ns in &candidates {
+            if !failed.iter().any(|(server, _)| server == ns) {
+                self.infra.mark_down(*ns);
+            }
+        }
+        if let Some((_, response)) = failed.pop() {
+            return Ok(response);
+        }
+        Err(last_err.unwrap_or_else(|| "no name servers to query".into()))
+    }
+
+    /// Forwarded query to a delegate name server
+    pub fn lookup(&self, qname: &str, qtype: QueryType, server: SocketAddr) -> Result<DnsPacket> {
+        let mut ctx = ResolutionContext::new(&self.config);
+        self.query(qname, qtype, server, self.config.timeout, &mut ctx)
+    }
+
+    /// Send a single question to `server`. The caller accounts for the
+    /// first query, further ones asked because of case randomization are
+    /// spent from `ctx` here.
+    fn query(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        server: SocketAddr,
+        timeout: Duration,
+        ctx: &mut ResolutionContext,
+    ) -> Result<DnsPacket> {
+        loop {
+            let randomize = self.config.case_randomization && !self.infra.ignores_case(server.ip());
+            let name = if randomize {
+                randomize_case(qname)
+            } else {
+                qname.to_owned()
+            };
+
+            // all 16 bits, so the ID is as hard to guess as it gets
+            let id: u16 = thread_rng().gen();
+
+            let packet = DnsPacket::query(name.as_str(), qtype)
+                .id(id)
+                .recursion_desired(true)
+                .build();
+
+            let mut response = self.transport.query(&packet, server, timeout)?;
+            if response.header.tc {
+                log::debug!("truncated response from {}, retrying over TCP", server);
+                response = self.transport.query_tcp(&packet, server, timeout)?;
+            }
+
+            let echoed = response.questions.first().map(|q| q.name.as_str());
+            if !randomize {
+                return Ok(response);
+            }
+            if echoed == Some(name.as_str()) {
+                self.infra.case_preserved(server.ip());
+                return Ok(response);
+            }
+            // a server that folds names to one case doesn't preserve it,
+            // which takes more than one response to believe. Any other
+            // difference is taken for a spoofed response.
+            if !echoed
+                .is_some_and(|n| n == qname.to_ascii_lowercase() || n == qname.to_ascii_uppercase())
+            {
+                return Err(format!(
+                    "response from {} echoes {:?} instead of {}",
+                    server, echoed, name
+                )
+                .into());
+            }
+            if self.infra.case_folded(server.ip()) {
+                log::info!(
+                    "{} doesn't preserve the case of names, asking it without",
+                    server
+                );
+            }
+            ctx.spend(&self.config)?;
+        }
+    }
+}
+
+/// number of labels in `name`, none for the root
+fn label_count(name: &str) -> usize {
+    if name.is_empty() {
+        0
+    } else {
+        name.split('.').count()
+    }
+}
+
+/// the name made of the last `n` labels of `name`
+fn last_labels(name: &str, n: usize) -> String {
+    let labels: Vec<&str> = name.split('.').collect();
+    labels[labels.len().saturating_sub(n)..].join(".")
+}
+
+/// `name` with the case of every letter picked at random
+fn randomize_case(name: &str) -> String {
+    let mut rng = thread_rng();
+    name.chars()
+        .map(|c| {
+            if rng.gen() {
+                c.to_ascii_uppercase()
+            } else {
+                c.to_ascii_lowercase()
+            }
+        })
+        .collect()
+}
+
+/// the address of an A or AAAA record
+fn address(record: &DnsRecord) -> Option<IpAddr> {
+    match *record {
+        DnsRecord::A { addr, .. } => Some(IpAddr::V4(addr)),
+        DnsRecord::AAAA { addr, .. } => Some(IpAddr::V6(addr)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AddressFamily, Resolver, ResolverConfig, Result};
+    use crate::dns::{DnsPacket, DnsRecord, QueryType, ResultCode};
+    use crate::infra::InfraConfig;
+    use crate::roothints::RootHints;
+    use crate::testing::{example_hierarchy, FakeNetwork, Zone};
+    use crate::transport::Transport;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    /// a single root, servers asked in the order they are listed
+    fn config() -> ResolverConfig {
+        ResolverConfig {
+            root_hints: RootHints::from_addrs(["198.41.0.4".parse().unwrap()]),
+            infra: InfraConfig {
+                exploration: 0.0,
+                ..InfraConfig::default()
+            },
+            ..ResolverConfig::default()
+        }
+    }
+
+    fn resolver() -> (Resolver, Arc<FakeNetwork>) {
+        let network = Arc::new(example_hierarchy());
+        let resolver = Resolver::with_transport(config(), network.clone());
+        (resolver, network)
+    }
+
+    fn first_a(records: &[DnsRecord]) -> Option<Ipv4Addr> {
+        records.iter().find_map(|r| match r {
+            DnsRecord::A { addr, .. } => Some(*addr),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn delegation_with_glue() {
+        let (resolver, network) = resolver();
+        let result = resolver.resolve("www.example.com", QueryType::A).unwrap();
+
+        assert_eq!(result.rcode, ResultCode::NOERROR);
+        assert_eq!(first_a(&result.answers), Some(Ipv4Addr::new(192, 0, 2, 80)));
+        // root, com and example.com
+        assert_eq!(network.query_count(), 3);
+    }
+
+    #[test]
+    fn cached_answer() {
+        let (resolver, network) = resolver();
+        let first = resolver.resolve("www.example.com", QueryType::A).unwrap();
+        network.clear_log();
+
+        let second = resolver.resolve("WWW.example.com", QueryType::A).unwrap();
+        assert_eq!(second.answers, first.answers);
+        assert_eq!(network.query_count(), 0);
+    }
+
+    #[test]
+    fn start_at_cached_delegation() {
+        let (resolver, network) = resolver();
+        resolver.resolve("www.example.com", QueryType::A).unwrap();
+        network.clear_log();
+
+        resolver.resolve("mail.example.com", QueryType::A).unwrap();
+        assert_eq!(network.queries_to("192.0.2.53"), 1);
+        assert_eq!(network.query_count(), 1);
+
+        // only the com cut is shared with this one
+        network.clear_log();
+        resolver.resolve("www.example.org", QueryType::A).unwrap();
+        assert_eq!(network.queries_to("192.5.6.30"), 0);
+        network.clear_log();
+        resolver.resolve("www.example.org", QueryType::TXT).unwrap();
+        assert_eq!(network.queries().len(), 1);
+    }
+
+    #[test]
+    fn glueless_delegation() {
+        let (resolver, network) = resolver();
+        let result = resolver.resolve("www.example.org", QueryType::A).unwrap();
+
+        assert_eq!(first_a(&result.answers), Some(Ipv4Addr::new(192, 0, 2, 81)));
+        // the name server of example.org had to be resolved on the way
+        assert!(network
+            .queries()
+            .iter()
+            .any(|(_, q)| q.name == "ns2.example.com"));
+    }
+
+    #[test]
+    fn cname() {
+        let (resolver, _) = resolver();
+        let result = resolver.resolve("alias.example.com", QueryType::A).unwrap();
+
+        assert_eq!(first_a(&result.answers), Some(Ipv4Addr::new(192, 0, 2, 80)));
+        assert!(matches!(
+            &result.answers[0],
+            DnsRecord::CNAME { domain, host, .. } if domain == "alias.example.com" && host == "www.example.com"
+        ));
+        assert_eq!(result.answers.len(), 2);
+    }
+
+    #[test]
+    fn cname_chain_across_zones() {
+        let (resolver, _) = resolver();
+        let result = resolver.resolve("ext.example.com", QueryType::A).unwrap();
+
+        let owners: Vec<&str> = result.answers.iter().map(|r| r.domain()).collect();
+        assert_eq!(
+            owners,
+            ["ext.example.com", "alias.example.org", "www.example.org"]
+        );
+        assert_eq!(first_a(&result.answers), Some(Ipv4Addr::new(192, 0, 2, 81)));
+
+        // and the same from the cache
+        let cached = resolver.resolve("ext.example.com", QueryType::A).unwrap();
+        assert_eq!(cached.answers.len(), 3);
+    }
+
+    #[test]
+    fn cname_loop() {
+        let (resolver, _) = resolver();
+        assert!(resolver.resolve("loop1.example.com", QueryType::A).is_err());
+
+        let config = ResolverConfig {
+            max_cname_chain: 1,
+            ..config()
+        };
+        let resolver = Resolver::with_transport(config, Arc::new(example_hierarchy()));
+        assert!(resolver.resolve("ext.example.com", QueryType::A).is_err());
+    }
+
+    /// Passes queries on to a fake network and slips `records` into the
+    /// answer and additional sections of every response from `from`.
+    struct Poisoning {
+        network: Arc<FakeNetwork>,
+        from: IpAddr,
+        records: Vec<DnsRecord>,
+    }
+
+    impl Transport for Poisoning {
+        fn query(
+            &self,
+            query: &DnsPacket,
+            server: SocketAddr,
+            timeout: Duration,
+        ) -> Result<DnsPacket> {
+            let mut response = self.network.query(query, server, timeout)?;
+            if server.ip() == self.from {
+                response.answers.extend(self.records.iter().cloned());
+                response.resources.extend(self.records.iter().cloned());
+            }
+            Ok(response)
+        }
+
+        fn query_tcp(
+            &self,
+            query: &DnsPacket,
+            server: SocketAddr,
+            timeout: Duration,
+        ) -> Result<DnsPacket> {
+            self.query(query, server, timeout)
+        }
+    }
+
+    #[test]
+    fn records_outside_bailiwick_are_dropped() {
+        let poison = |domain: &str| DnsRecord::A {
+            domain: domain.into(),
+            addr: Ipv4Addr::new(203, 0, 113, 66),
+            ttl: 86400,
+        };
+        let network = Arc::new(example_hierarchy());
+        let transport = Arc::new(Poisoning {
+            network: network.clone(),
+            from: "192.0.2.53".parse().unwrap(),
+            records: vec![poison("www.example.org"), poison("a0.org-servers.net")],
+        });
+        let resolver = Resolver::with_transport(config(), transport);
+
+        let result = resolver.resolve("www.example.com", QueryType::A).unwrap();
+        assert_eq!(result.answers.len(), 1);
+        assert!(resolver
+            .cache()
+            .get("www.example.org", QueryType::A)
+            .is_none());
+        assert!(resolver
+            .delegations()
+            .get("a0.org-servers.net", QueryType::A)
+            .is_none());
+
+        let result = resolver.resolve("www.example.org", QueryType::A).unwrap();
+        assert_eq!(first_a(&result.answers), Some(Ipv4Addr::new(192, 0, 2, 81)));
+    }
+
+    #[test]
+    fn out_of_zone_glue_is_resolved() {
+        // com hands out an address for a name server under org, which
+        // isn't its to give
+        let network = Arc::new(
+            FakeNetwork::new()
+                .server(
+                    "198.41.0.4",
+                    Zone::parse(
+                        "",
+                        "@ SOA a.root-servers.net. nstld.verisign-grs.com. 1 1800 900 604800 86400
+                         com. NS a.gtld-servers.net.
+                         org. NS a0.org-servers.net.
+                         a.gtld-servers.net. A 192.5.6.30
+                         a0.org-servers.net. A 199.19.56.1",
+                    ),
+                )
+                .server(
+                    "192.5.6.30",
+                    Zone::parse(
+                        "com",
+                        "@ SOA a.gtld-servers.net. nstld.verisign-grs.com. 1 1800 900 604800 86400
+                         other NS ns.example.org.
+                         ns.example.org. A 203.0.113.66",
+                    ),
+                )
+                .server(
+                    "199.19.56.1",
+                    Zone::parse(
+                        "org",
+                        "@ SOA a0.org-servers.net. noc.afilias-nst.info. 1 1800 900 604800 86400
+                         example NS ns.example
+                         ns.example A 192.0.2.54",
+                    ),
+                )
+                .server(
+                    "192.0.2.54",
+                    Zone::parse(
+                        "example.org",
+                        "@ SOA ns hostmaster 1 7200 3600 1209600 300
+                         ns A 192.0.2.54",
+                    ),
+                )
+                .server(
+                    "192.0.2.54",
+                    Zone::parse(
+                        "other.com",
+                        "@ SOA ns.example.org. hostmaster 1 7200 3600 1209600 300
+                         www A 192.0.2.82",
+                    ),
+                ),
+        );
+        let resolver = Resolver::with_transport(config(), network.clone());
+
+        let result = resolver.resolve("www.other.com", QueryType::A).unwrap();
+        assert_eq!(first_a(&result.answers), Some(Ipv4Addr::new(192, 0, 2, 82)));
+        assert_eq!(network.queries_to("203.0.113.66"), 0);
+        assert!(network
+            .queries()
+            .iter()
+            .any(|(_, q)| q.name == "ns.example.org"));
+    }
+
+    /// Echoes questions with the case of every letter swapped, like a
+    /// spoofer that guessed everything but the case would.
+    struct Recasing(Arc<FakeNetwork>);
+
+    impl Transport for Recasing {
+        fn query(
+            &self,
+            query: &DnsPacket,
+            server: SocketAddr,
+            timeout: Duration,
+        ) -> Result<DnsPacket> {
+            let mut response = self.0.query(query, server, timeout)?;
+            for question in &mut response.questions {
+                question.name = question
+                    .name
+                    .chars()
+                    .map(|c| {
+                        if c.is_ascii_lowercase() {
+                            c.to_ascii_uppercase()
+                        } else {
+                            c.to_ascii_lowercase()
+                        }
+                    })
+                    .collect();
+            }
+            Ok(response)
+        }
+
+        fn query_tcp(
+            &self,
+            query: &DnsPacket,
+            server: SocketAddr,
+            timeout: Duration,
+        ) -> Result<DnsPacket> {
+            self.query(query, server, timeout)
+        }
+    }
+
+    #[test]
+    fn case_randomization() {
+        let config = ResolverConfig {
+            case_randomization: true,
+            ..config()
+        };
+        // long names, so that a random case all in lowercase is unlikely
+        // enough to not matter
+        let network = Arc::new(example_hierarchy().lowercasing("192.5.6.30").server(
+            "192.0.2.53",
+            Zone::parse(
+                "example.com",
+                "thequickbrownfoxjumpsoverthelazydog A 192.0.2.90",
+            ),
+        ));
+        let resolver = Resolver::with_transport(config.clone(), network.clone());
+
+        let name = "thequickbrownfoxjumpsoverthelazydog.example.com";
+        let result = resolver.resolve(name, QueryType::A).unwrap();
+        assert_eq!(first_a(&result.answers), Some(Ipv4Addr::new(192, 0, 2, 90)));
+        // com lost the case and was asked again without it
+        let com: IpAddr = "192.5.6.30".parse().unwrap();
+        assert!(
+            network
+                .queries()
+                .iter()
+                .all(|(ns, q)| q.name.eq_ignore_ascii_case(name)
+                    && (q.name != name || ns.ip() == com))
+        );
+        assert!(resolver.infra().ignores_case(com));
+        // twice randomized to be sure, then once without
+        assert_eq!(network.queries_to("192.5.6.30"), 3);
+        assert!(!resolver.infra().ignores_case("192.0.2.53".parse().unwrap()));
+
+        // answers echoing another case are rejected
+        let resolver = Resolver::with_transport(config, Arc::new(Recasing(network)));
+        assert!(resolver.resolve(name, QueryType::A).is_err());
+    }
+
+    #[test]
+    fn qname_minimisation() {
+        let config = ResolverConfig {
+            qname_minimisation: true,
+            ..config()
+        };
+        let network = Arc::new(example_hierarchy());
+        let resolver = Resolver::with_transport(config, network.clone());
+
+        let result = resolver.resolve("www.example.com", QueryType::A).unwrap();
+        assert_eq!(first_a(&result.answers), Some(Ipv4Addr::new(192, 0, 2, 80)));
+        let asked: Vec<(String, String)> = network
+            .queries()
+            .into_iter()
+            .map(|(ns, q)| (ns.ip().to_string(), format!("{:?} {}", q.qtype, q.name)))
+            .collect();
+        let expected = [
+            ("198.41.0.4", "A com"),
+            ("192.5.6.30", "A example.com"),
+            ("192.0.2.53", "A www.example.com"),
+        ];
+        assert_eq!(
+            asked,
+            expected.map(|(ns, q)| (ns.to_string(), q.to_string()))
+        );
+
+        // `sub.example.com` is an empty non-terminal inside the zone
+        network.clear_log();
+        let result = resolver
+            .resolve("deep.sub.example.com", QueryType::TXT)
+            .unwrap();
+        assert_eq!(result.answers.len(), 1);
+        let names: Vec<String> = network.queries().into_iter().map(|(_, q)| q.name).collect();
+        assert_eq!(names, vec!["sub.example.com", "deep.sub.example.com"]);
+    }
+
+    /// Answers NXDOMAIN where there is no data from `from`, like servers
+    /// that get empty non-terminals wrong.
+    struct BrokenNonTerminals {
+        network: Arc<FakeNetwork>,
+        from: IpAddr,
+    }
+
+    impl Transport for BrokenNonTerminals {
+        fn query(
+            &self,
+            query: &DnsPacket,
+            server: SocketAddr,
+            timeout: Duration,
+        ) -> Result<DnsPacket> {
+            let mut response = self.network.query(query, server, timeout)?;
+            if server.ip() == self.from && response.is_nodata() {
+                response.header.rcode = ResultCode::NXDOMAIN;
+            }
+            Ok(response)
+        }
+
+        fn query_tcp(
+            &self,
+            query: &DnsPacket,
+            server: SocketAddr,
+            timeout: Duration,
+        ) -> Result<DnsPacket> {
+            self.query(query, server, timeout)
+        }
+    }
+
+    #[test]
+    fn qname_minimisation_fallback() {
+        let config = ResolverConfig {
+            qname_minimisation: true,
+            ..config()
+        };
+        let network = Arc::new(example_hierarchy());
+        let transport = Arc::new(BrokenNonTerminals {
+            network: network.clone(),
+            from: "192.0.2.53".parse().unwrap(),
+        });
+        let resolver = Resolver::with_transport(config, transport);
+
+        let result = resolver
+            .resolve("deep.sub.example.com", QueryType::TXT)
+            .unwrap();
+        assert_eq!(result.rcode, ResultCode::NOERROR);
+        assert_eq!(result.answers.len(), 1);
+        assert_eq!(network.queries_to("192.0.2.53"), 2);
+    }
+
+    #[test]
+    fn other_types() {
+        let (resolver, _) = resolver();
+        let answer = |name, qtype| {
+            let result = resolver.resolve(name, qtype).unwrap();
+            assert_eq!(result.rcode, ResultCode::NOERROR);
+            result.answers.iter().map(|r| r.qtype()).collect::<Vec<_>>()
+        };
+
+        assert_eq!(answer("example.com", QueryType::MX), [QueryType::MX]);
+        assert_eq!(answer("example.com", QueryType::NS), [QueryType::NS]);
+        assert_eq!(answer("example.com", QueryType::SOA), [QueryType::SOA]);
+        assert_eq!(
+            answer("deep.sub.example.com", QueryType::TXT),
+            [QueryType::TXT]
+        );
+        // NODATA
+        assert_eq!(answer("www.example.com", QueryType::AAAA), []);
+        assert_eq!(answer("sub.example.com", QueryType::A), []);
+    }
+
+    #[test]
+    fn nxdomain() {
+        let (resolver, _) = resolver();
+        let result = resolver.resolve("nope.example.com", QueryType::A).unwrap();
+
+        assert_eq!(result.rcode, ResultCode::NXDOMAIN);
+        assert!(result.answers.is_empty());
+        assert!(matches!(result.authorities[0], DnsRecord::SOA { .. }));
+    }
+
+    #[test]
+    fn cached_nxdomain() {
+        let (resolver, network) = resolver();
+        resolver.resolve("nope.example.com", QueryType::A).unwrap();
+        resolver.resolve("www.example.com", QueryType::MX).unwrap();
+        network.clear_log();
+
+        let nxdomain = resolver.resolve("nope.example.com", QueryType::A).unwrap();
+        assert_eq!(nxdomain.rcode, ResultCode::NXDOMAIN);
+        assert!(matches!(nxdomain.authorities[0], DnsRecord::SOA { .. }));
+        let nodata = resolver.resolve("www.example.com", QueryType::MX).unwrap();
+        assert_eq!(nodata.rcode, ResultCode::NOERROR);
+        assert!(nodata.answers.is_empty());
+        assert_eq!(network.query_count(), 0);
+    }
+
+    #[test]
+    fn mutually_dependent_delegations() {
+        // the name servers of each zone live in the other one
+        let network = Arc::new(FakeNetwork::new().server(
+            "198.41.0.4",
+            Zone::parse(
+                "",
+                "@ SOA a.root-servers.net. nstld.verisign-grs.com. 1 1800 900 604800 86400
+                 a.test. NS ns.b.test.
+                 b.test. NS ns.a.test.",
+            ),
+        ));
+        let resolver = Resolver::with_transport(config(), network.clone());
+        let result = resolver.resolve("www.a.test", QueryType::A).unwrap();
+
+        assert_eq!(result.rcode, ResultCode::SERVFAIL);
+        // www.a.test, ns.b.test and ns.a.test once each
+        assert_eq!(network.query_count(), 3);
+    }
+
+    #[test]
+    fn query_budget_and_deadline() {
+        let limited = |config: ResolverConfig| {
+            let network = Arc::new(example_hierarchy());
+            let resolver = Resolver::with_transport(config, network.clone());
+            let result = resolver.resolve("www.example.com", QueryType::A).unwrap();
+            (result.rcode, network.query_count())
+        };
+
+        let budget = ResolverConfig {
+            max_queries: 2,
+            ..config()
+        };
+        assert_eq!(limited(budget), (ResultCode::SERVFAIL, 2));
+
+        let deadline = ResolverConfig {
+            resolution_timeout: Duration::ZERO,
+            ..config()
+        };
+        assert_eq!(limited(deadline), (ResultCode::SERVFAIL, 0));
+    }
+
+    /// `failover.test` with a dead and a working name server,
+    /// `dead.test` whose only name server is the dead one and
+    /// `refused.test` listing a server that refuses before a working one
+    fn failover_network() -> Arc<FakeNetwork> {
+        Arc::new(
+            FakeNetwork::new()
+                .server(
+                    "198.41.0.4",
+                    Zone::parse(
+                        "",
+                        "@ SOA a.root-servers.net. nstld.verisign-grs.com. 1 1800 900 604800 86400
+                         failover.test. NS ns1.failover.test.
+                         failover.test. NS ns2.failover.test.
+                         dead.test. NS ns1.failover.test.
+                         refused.test. NS ns3.failover.test.
+                         refused.test. NS ns2.failover.test.
+                         ns1.failover.test. A 192.0.2.98
+                         ns2.failover.test. A 192.0.2.99
+                         ns3.failover.test. A 192.0.2.97",
+                    ),
+                )
+                .server(
+                    "192.0.2.97",
+                    Zone::parse(
+                        "other.test",
+                        "@ SOA ns3.failover.test. hostmaster 1 7200 3600 1209600 300",
+                    ),
+                )
+                .server(
+                    "192.0.2.99",
+                    Zone::parse(
+                        "failover.test",
+                        "@ SOA ns2 hostmaster 1 7200 3600 1209600 300
+                         www A 192.0.2.80
+                         mail A 192.0.2.25",
+                    ),
+                )
+                .server(
+                    "192.0.2.99",
+                    Zone::parse(
+                        "refused.test",
+                        "@ SOA ns2.failover.test. hostmaster 1 7200 3600 1209600 300
+                         www A 192.0.2.81",
+                    ),
+                ),
+        )
+    }
+
+    #[test]
+    fn failover_to_other_servers() {
+        let network = failover_network();
+        let resolver = Resolver::with_transport(config(), network.clone());
+        let result = resolver.resolve("www.failover.test", QueryType::A).unwrap();
+
+        assert_eq!(first_a(&result.answers), Some(Ipv4Addr::new(192, 0, 2, 80)));
+        assert_eq!(network.queries_to("192.0.2.98"), 1);
+        let dead = "192.0.2.98".parse().unwrap();
+        assert_eq!(resolver.infra().get(dead).unwrap().failures, 1);
+        assert!(!resolver.infra().is_down(dead));
+    }
+
+    #[test]
+    fn failover_on_refused() {
+        let network = failover_network();
+        let resolver = Resolver::with_transport(config(), network.clone());
+        let result = resolver.resolve("www.refused.test", QueryType::A).unwrap();
+
+        assert_eq!(result.rcode, ResultCode::NOERROR);
+        assert_eq!(first_a(&result.answers), Some(Ipv4Addr::new(192, 0, 2, 81)));
+        // a server that answers isn't asked again on retries
+        assert_eq!(network.queries_to("192.0.2.97"), 1);
+        let refusing = "192.0.2.97".parse().unwrap();
+        assert_eq!(resolver.infra().get(refusing).unwrap().failures, 1);
+
+        // with no other server, the refusal is what comes back
+        let network = Arc::new(FakeNetwork::new().server(
+            "198.41.0.4",
+            Zone::parse(
+                "test",
+                "@ SOA a.root-servers.net. nstld.verisign-grs.com. 1 1800 900 604800 86400",
+            ),
+        ));
+        let resolver = Resolver::with_transport(config(), network.clone());
+        let result = resolver.resolve("www.example.com", QueryType::A).unwrap();
+        assert_eq!(result.rcode, ResultCode::REFUSED);
+        assert_eq!(network.query_count(), 1);
+    }
+
+    #[test]
+    fn retries_then_marked_down() {
+        let network = failover_network();
+        let resolver = Resolver::with_transport(config(), network.clone());
+        assert!(resolver.resolve("www.dead.test", QueryType::A).is_err());
+
+        // the first try and two retries
+        assert_eq!(network.queries_to("192.0.2.98"), 3);
+        assert!(resolver.infra().is_down("192.0.2.98".parse().unwrap()));
+
+        // skipped while there is another server
+        resolver
+            .resolve("mail.failover.test", QueryType::A)
+            .unwrap();
+        assert_eq!(network.queries_to("192.0.2.98"), 3);
+    }
+
+    /// an IPv6-only lab: only the IPv6 addresses have servers behind them.
+    /// `glueless.test` is served by a name server with just an AAAA record
+    fn ipv6_network() -> Arc<FakeNetwork> {
+        Arc::new(
+            FakeNetwork::new()
+                .server(
+                    "2001:db8::53",
+                    Zone::parse(
+                        "",
+                        "@ SOA ns.root. hostmaster.root. 1 1800 900 604800 86400
+                         @ NS ns.root.
+                         ns.root. AAAA 2001:db8::53
+                         ns.root. A 192.0.2.53
+                         lab.test. NS ns.lab.test.
+                         glueless.test. NS ns6.lab.test.
+                         ns.lab.test. AAAA 2001:db8::54
+                         ns.lab.test. A 192.0.2.54",
+                    ),
+                )
+                .server(
+                    "2001:db8::54",
+                    Zone::parse(
+                        "lab.test",
+                        "@ SOA ns hostmaster 1 7200 3600 1209600 300
+                         www AAAA 2001:db8::80
+                         ns6 AAAA 2001:db8::55",
+                    ),
+                )
+                .server(
+                    "2001:db8::55",
+                    Zone::parse(
+                        "glueless.test",
+                        "@ SOA ns6.lab.test. hostmaster.lab.test. 1 7200 3600 1209600 300
+                         www AAAA 2001:db8::81",
+                    ),
+                ),
+        )
+    }
+
+    #[test]
+    fn ipv6_only() {
+        for family in [AddressFamily::V6Only, AddressFamily::PreferV6] {
+            let network = ipv6_network();
+            let config = ResolverConfig {
+                root_hints: RootHints::from_addrs(
+                    ["192.0.2.53", "2001:db8::53"]
+                        .iter()
+                        .map(|a| a.parse().unwrap()),
+                ),
+                address_family: family,
+                ..config()
+            };
+            let resolver = Resolver::with_transport(config, network.clone());
+
+            let result = resolver.resolve("www.lab.test", QueryType::AAAA).unwrap();
+            assert_eq!(result.answers.len(), 1);
+            let result = resolver
+                .resolve("www.glueless.test", QueryType::AAAA)
+                .unwrap();
+            assert_eq!(result.answers.len(), 1);
+
+            // nothing was sent over IPv4
+            assert!(network.queries().iter().all(|(server, _)| server.is_ipv6()));
+        }
+
+        let config = ResolverConfig {
+            root_hints: RootHints::from_addrs(["2001:db8::53".parse().unwrap()]),
+            address_family: AddressFamily::V4Only,
+            ..config()
+        };
+        let resolver = Resolver::with_transport(config, ipv6_network());
+        assert!(resolver.resolve("www.lab.test", QueryType::AAAA).is_err());
+    }
+
+    #[test]
+    fn truncated_response_retried_over_tcp() {
+        let network = Arc::new(example_hierarchy().truncating("192.0.2.53"));
+        let resolver = Resolver::with_transport(config(), network.clone());
+        let result = resolver.resolve("www.example.com", QueryType::A).unwrap();
+
+        assert_eq!(first_a(&result.answers), Some(Ipv4Addr::new(192, 0, 2, 80)));
+        let tcp = network.tcp_queries();
+        assert_eq!(tcp.len(), 1);
+        assert_eq!(tcp[0].1.name, "www.example.com");
+    }
+
+    #[test]
+    fn unreachable_root() {
+        let config = ResolverConfig {
+            root_hints: RootHints::from_addrs(["192.0.2.1".parse().unwrap()]),
+            ..ResolverConfig::default()
+        };
+        let resolver = Resolver::with_transport(config, Arc::new(example_hierarchy()));
+        assert!(resolver.resolve("www.example.com", QueryType::A).is_err());
+    }
+
+    /// the hierarchy with an outdated root server at 192.0.2.100, which
+    /// still knows the current root at 198.41.0.4
+    fn renumbered_root(ttl: u32) -> (Resolver, Arc<FakeNetwork>) {
+        let zone = format!(
+            "@ {ttl} NS a.root-servers.net.\na.root-servers.net. A 198.41.0.4",
+            ttl = ttl
+        );
+        let network = Arc::new(example_hierarchy().server("192.0.2.100", Zone::parse("", &zone)));
+        let config = ResolverConfig {
+            root_hints: RootHints::from_addrs(["192.0.2.100".parse().unwrap()]),
+            ..ResolverConfig::default()
+        };
+        (Resolver::with_transport(config, network.clone()), network)
+    }
+
+    #[test]
+    fn priming_replaces_hints() {
+        let (resolver, network) = renumbered_root(3600);
+        let hints = resolver.prime().unwrap();
+        assert_eq!(hints.servers[0].name, "a.root-servers.net");
+        assert_eq!(hints.addrs(), vec!["198.41.0.4".parse::<IpAddr>().unwrap()]);
+        assert_eq!(resolver.root_hints(), hints);
+
+        let result = resolver.resolve("www.example.com", QueryType::A).unwrap();
+        assert_eq!(first_a(&result.answers), Some(Ipv4Addr::new(192, 0, 2, 80)));
+        assert_eq!(network.queries_to("192.0.2.100"), 1);
+        assert_eq!(network.queries_to("198.41.0.4"), 1);
+    }
+
+    #[test]
+    fn reprime_after_expiry() {
+        let (resolver, network) = renumbered_root(0);
+        resolver.prime().unwrap();
+        resolver.resolve("www.example.com", QueryType::A).unwrap();
+        // a TTL of zero still keeps the primed set for a while
+        assert_eq!(network.queries_to("192.0.2.100"), 1);
+
+        resolver.roots.write().unwrap().as_mut().unwrap().expires = Instant::now();
+        resolver.resolve("www.example.org", QueryType::A).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while resolver.repriming.load(Ordering::SeqCst) && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        resolver.resolve("www.example.net", QueryType::A).unwrap();
+        // primed again once in the background, while the expired set
+        // kept answering
+        assert_eq!(network.queries_to("192.0.2.100"), 2);
+        assert_eq!(network.queries_to("198.41.0.4"), 3);
+        assert!(resolver.roots.read().unwrap().as_ref().unwrap().expires > Instant::now());
+    }
+
+    #[test]
+    fn priming_failure() {
+        let config = ResolverConfig {
+            root_hints: RootHints::from_addrs(["192.0.2.1".parse().unwrap()]),
+            ..ResolverConfig::default()
+        };
+        let resolver = Resolver::with_transport(config.clone(), Arc::new(example_hierarchy()));
+        assert!(resolver.prime().is_err());
+        assert_eq!(resolver.root_hints(), config.root_hints);
+    }
+
+    #[test]
+    fn rotate_roots_on_failure() {
+        // only one of the roots answers, whichever order they are tried in
+        let config = ResolverConfig {
+            root_hints: RootHints::from_addrs(
+                ["192.0.2.1", "2001:db8::1", "198.41.0.4", "192.0.2.2"]
+                    .iter()
+                    .map(|a| a.parse().unwrap()),
+            ),
+            ..ResolverConfig::default()
+        };
+        for _ in 0..8 {
+            let resolver = Resolver::with_transport(config.clone(), Arc::new(example_hierarchy()));
+            let result = resolver.resolve("www.example.com", QueryType::A).unwrap();
+            assert_eq!(first_a(&result.answers), Some(Ipv4Addr::new(192, 0, 2, 80)));
+        }
+    }
+}
@@ -0,0 +1,110 @@
+//! Root server hints, the starting point of every resolution.
+use std::net::IpAddr;
+use std::path::Path;
+
+use crate::dns::DnsRecord;
+use crate::zone::{ZoneError, ZoneParser};
+
+/// the IANA `named.root` file compiled into the crate
+const NAMED_ROOT: &str = include_str!("named.root");
+
+/// A root name server and its addresses.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RootServer {
+    pub name: String,
+    pub addrs: Vec<IpAddr>,
+}
+
+/// The set of root servers a resolver starts from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RootHints {
+    pub servers: Vec<RootServer>,
+}
+
+impl Default for RootHints {
+    fn default() -> Self {
+        RootHints::builtin()
+    }
+}
+
+impl RootHints {
+    /// hints from the compiled-in copy of IANA's `named.root`
+    pub fn builtin() -> RootHints {
+        RootHints::parse(NAMED_ROOT).expect("compiled-in named.root is valid")
+    }
+
+    /// parse hints in `named.root` format: NS records for the root plus
+    /// the A and AAAA records of the servers they name
+    pub fn parse(text: &str) -> Result<RootHints, ZoneError> {
+        let records = ZoneParser::new().origin(".").parse_str(text)?;
+        Ok(RootHints::from_records(&records))
+    }
+
+    /// read hints from a `named.root` format file
+    pub fn from_file(path: impl AsRef<Path>) -> Result<RootHints, ZoneError> {
+        let records = ZoneParser::new().origin(".").parse_file(path)?;
+        Ok(RootHints::from_records(&records))
+    }
+
+    /// hints from the root NS records and their address records
+    pub fn from_records(records: &[DnsRecord]) -> RootHints {
+        let servers = records
+            .iter()
+            .filter_map(|r| match r {
+                DnsRecord::NS { domain, host, .. } if domain.is_empty() => Some(host),
+                _ => None,
+            })
+            .map(|host| RootServer {
+                name: host.clone(),
+                addrs: records
+                    .iter()
+                    .filter(|r| r.domain() == host)
+                    .filter_map(|r| match *r {
+                        DnsRecord::A { addr, .. } => Some(IpAddr::V4(addr)),
+                        DnsRecord::AAAA { addr, .. } => Some(IpAddr::V6(addr)),
+                        _ => None,
+                    })
+                    .collect(),
+            })
+            .collect();
+        RootHints { servers }
+    }
+
+    /// a private root, e.g. for lab environments, given by address only
+    pub fn from_addrs(addrs: impl IntoIterator<Item = IpAddr>) -> RootHints {
+        let servers = addrs
+            .into_iter()
+            .map(|addr| RootServer {
+                name: addr.to_string(),
+                addrs: vec![addr],
+            })
+            .collect();
+        RootHints { servers }
+    }
+
+    /// every address of every root server, IPv4 and IPv6
+    pub fn addrs(&self) -> Vec<IpAddr> {
+        self.servers
+            .iter()
+            .flat_map(|s| s.addrs.iter().copied())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RootHints;
+    use std::net::IpAddr;
+
+    #[test]
+    fn builtin_hints() {
+        let hints = RootHints::builtin();
+        assert_eq!(hints.servers.len(), 13);
+        assert_eq!(hints.servers[0].name, "a.root-servers.net");
+
+        let addrs = hints.addrs();
+        assert_eq!(addrs.len(), 26);
+        assert!(addrs.contains(&"198.41.0.4".parse::<IpAddr>().unwrap()));
+        assert!(addrs.contains(&"2001:dc3::35".parse::<IpAddr>().unwrap()));
+    }
+}
@@ -0,0 +1,231 @@
+//! Resource record sets: all records sharing owner name, type and class.
+use std::cmp::Ordering;
+
+use crate::dns::{DnsRecord, QueryType};
+use crate::rdata::{self, Rdata};
+
+/// A set of records with the same owner, type and class. Per RFC 2181 the
+/// records of a set share a single TTL.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct RRset {
+    pub name: String,
+    pub rtype: QueryType,
+    pub class: u16,
+    pub ttl: u32,
+    pub rdata: Vec<Box<dyn Rdata>>,
+}
+
+impl RRset {
+    pub fn new(name: impl Into<String>, rtype: QueryType, ttl: u32) -> RRset {
+        RRset {
+            name: name.into(),
+            rtype,
+            class: 1,
+            ttl,
+            rdata: Vec::new(),
+        }
+    }
+
+    /// a set holding only `record`, `None` for records without data
+    pub fn from_record(record: &DnsRecord) -> Option<RRset> {
+        let rdata = record.rdata()?;
+        let mut set = RRset::new(record.domain(), record.qtype(), record.ttl());
+        set.rdata.push(rdata);
+        Some(set)
+    }
+
+    /// whether `record` belongs to this set
+    pub fn matches(&self, record: &DnsRecord) -> bool {
+        record.qtype() == self.rtype && record.domain().eq_ignore_ascii_case(&self.name)
+    }
+
+    /// add a record's data. The set keeps the lowest TTL it has seen
+    pub fn push(&mut self, record: &DnsRecord) {
+        if let Some(rdata) = record.rdata() {
+            self.ttl = self.ttl.min(record.ttl());
+            self.rdata.push(rdata);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.rdata.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rdata.is_empty()
+    }
+
+    /// remove duplicate data, keeping the first occurrence
+    pub fn dedup(&mut self) {
+        let mut seen = Vec::with_capacity(self.rdata.len());
+        self.rdata.retain(|r| {
+            let wire = canonical_rdata(&**r);
+            if seen.contains(&wire) {
+                false
+            } else {
+                seen.push(wire);
+                true
+            }
+        });
+    }
+
+    /// sort the data in DNSSEC canonical order (RFC 4034 section 6.3)
+    pub fn sort_canonical(&mut self) {
+        self.rdata.sort_by_cached_key(|r| canonical_rdata(&**r));
+    }
+
+    /// the set as individual records, all carrying the set's TTL
+    pub fn records(&self) -> Vec<DnsRecord> {
+        self.rdata
+            .iter()
+            .map(|r| DnsRecord::from_rdata(self.name.clone(), self.ttl, r.clone()))
+            .collect()
+    }
+}
+
+/// Group records into sets, in order of first appearance. TTLs are
+/// normalised to the lowest of each set and duplicates are removed.
+/// Records of unknown types without data are skipped.
+pub fn group(records: &[DnsRecord]) -> Vec<RRset> {
+    let mut sets: Vec<RRset> = Vec::new();
+    for record in records {
+        match sets.iter_mut().find(|set| set.matches(record)) {
+            Some(set) => set.push(record),
+            None => {
+                if let Some(set) = RRset::from_record(record) {
+                    sets.push(set);
+                }
+            }
+        }
+    }
+    for set in sets.iter_mut() {
+        set.dedup();
+    }
+    sets
+}
+
+/// sort sets by canonical owner name order, then type, and sort the data
+/// of each set canonically
+pub fn sort_canonical(sets: &mut [RRset]) {
+    for set in sets.iter_mut() {
+        set.sort_canonical();
+    }
+    sets.sort_by(|a, b| {
+        canonical_name_cmp(&a.name, &b.name).then(a.rtype.to_num().cmp(&b.rtype.to_num()))
+    });
+}
+
+/// Canonical DNS name order (RFC 4034 section 6.1): names are compared
+/// label by label from the root, each label as case-insensitive octets.
+pub fn canonical_name_cmp(a: &str, b: &str) -> Ordering {
+    let labels = |name: &str| -> Vec<Vec<u8>> {
+        name.split('.')
+            .filter(|l| !l.is_empty())
+            .rev()
+            .map(|l| l.to_ascii_lowercase().into_bytes())
+            .collect()
+    };
+    labels(a).cmp(&labels(b))
+}
+
+/// canonical wire format of record data: uncompressed, with the names of
+/// the well known types lowercased
+fn canonical_rdata(data: &dyn Rdata) -> Vec<u8> {
+    let any = data.as_any();
+    let lowered: Option<Box<dyn Rdata>> = if let Some(r) = any.downcast_ref::<rdata::NS>() {
+        Some(Box::new(rdata::NS {
+            host: r.host.to_lowercase(),
+        }))
+    } else if let Some(r) = any.downcast_ref::<rdata::CNAME>() {
+        Some(Box::new(rdata::CNAME {
+            host: r.host.to_lowercase(),
+        }))
+    } else if let Some(r) = any.downcast_ref::<rdata::MX>() {
+        Some(Box::new(rdata::MX {
+            priority: r.priority,
+            host: r.host.to_lowercase(),
+        }))
+    } else {
+        any.downcast_ref::<rdata::SOA>().map(|r| {
+            Box::new(rdata::SOA {
+                m_name: r.m_name.to_lowercase(),
+                r_name: r.r_name.to_lowercase(),
+                ..r.clone()
+            }) as Box<dyn Rdata>
+        })
+    };
+    match lowered {
+        Some(r) => rdata::wire(&*r),
+        None => rdata::wire(data),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{canonical_name_cmp, group, sort_canonical};
+    use crate::dns::{DnsRecord, QueryType};
+    use std::net::Ipv4Addr;
+
+    fn a(domain: &str, last: u8, ttl: u32) -> DnsRecord {
+        DnsRecord::A {
+            domain: domain.into(),
+            addr: Ipv4Addr::new(192, 0, 2, last),
+            ttl,
+        }
+    }
+
+    #[test]
+    fn group_records() {
+        let records = vec![
+            a("www.example.com", 2, 300),
+            DnsRecord::CNAME {
+                domain: "alias.example.com".into(),
+                host: "www.example.com".into(),
+                ttl: 60,
+            },
+            a("www.example.com", 1, 120),
+            a("WWW.example.com", 2, 300),
+        ];
+
+        let sets = group(&records);
+        assert_eq!(sets.len(), 2);
+        assert_eq!(sets[0].rtype, QueryType::A);
+        assert_eq!(sets[0].ttl, 120);
+        assert_eq!(sets[0].len(), 2);
+        assert_eq!(
+            sets[0].records(),
+            vec![a("www.example.com", 2, 120), a("www.example.com", 1, 120)]
+        );
+        assert_eq!(sets[1].rtype, QueryType::CNAME);
+    }
+
+    #[test]
+    fn canonical_order() {
+        // the example ordering from RFC 4034 section 6.1
+        let ordered = [
+            "example",
+            "a.example",
+            "yljkjljk.a.example",
+            "Z.a.example",
+            "zABC.a.EXAMPLE",
+            "z.example",
+            "\u{1}.z.example",
+            "*.z.example",
+        ];
+        for pair in ordered.windows(2) {
+            assert!(canonical_name_cmp(pair[0], pair[1]).is_lt(), "{:?}", pair);
+        }
+
+        let mut sets = group(&[
+            a("b.example", 9, 60),
+            a("a.example", 7, 60),
+            a("a.example", 3, 60),
+        ]);
+        sort_canonical(&mut sets);
+        assert_eq!(sets[0].name, "a.example");
+        assert_eq!(
+            sets[0].records(),
+            vec![a("a.example", 3, 60), a("a.example", 7, 60)]
+        );
+    }
+}
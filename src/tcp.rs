@@ -0,0 +1,199 @@
+//! DNS over TCP framing (RFC 1035 section 4.2.2, RFC 7766): every message
+//! on a stream is preceded by its length as a 16 bit big endian integer.
+use std::io::{self, Cursor, Read, Write};
+
+use crate::dns::DnsPacket;
+use crate::packet::{PacketReader, PacketWriter};
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+/// Reads length prefixed messages from a stream.
+#[derive(Debug)]
+pub struct FramedReader<R> {
+    inner: R,
+}
+
+impl<R: Read> FramedReader<R> {
+    pub fn new(inner: R) -> Self {
+        FramedReader { inner }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// the next message as raw bytes, `None` if the stream ended cleanly
+    /// between two messages. A read timeout comes back as `WouldBlock` or
+    /// `TimedOut` only between messages, in the middle of one it's
+    /// `UnexpectedEof` as what was read of it is lost.
+    pub fn read_frame(&mut self) -> Result<Option<Vec<u8>>> {
+        let mut len = [0u8; 2];
+        let mut filled = 0;
+        while filled < len.len() {
+            match self.inner.read(&mut len[filled..]) {
+                Ok(0) if filled == 0 => return Ok(None),
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) if filled > 0 => return Err(cut_off(e).into()),
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        let mut frame = vec![0; u16::from_be_bytes(len) as usize];
+        self.inner.read_exact(&mut frame).map_err(cut_off)?;
+        Ok(Some(frame))
+    }
+
+    /// the next message, `None` if the stream ended cleanly between two
+    /// messages
+    pub fn read_message(&mut self) -> Result<Option<DnsPacket>> {
+        match self.read_frame()? {
+            Some(frame) => {
+                let mut reader = PacketReader::new(Cursor::new(frame));
+                DnsPacket::from_buffer(&mut reader).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+/// a timeout part way through a message can't be resumed
+fn cut_off(e: io::Error) -> io::Error {
+    match e.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "timed out in the middle of a message",
+        ),
+        _ => e,
+    }
+}
+
+/// Writes length prefixed messages to a stream.
+#[derive(Debug)]
+pub struct FramedWriter<W> {
+    inner: W,
+}
+
+impl<W: Write> FramedWriter<W> {
+    pub fn new(inner: W) -> Self {
+        FramedWriter { inner }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    /// write raw message bytes with their length in front, in one write
+    /// so a message isn't split over several segments needlessly
+    pub fn write_frame(&mut self, frame: &[u8]) -> Result<()> {
+        let len = u16::try_from(frame.len())
+            .map_err(|_| format!("message of {} bytes is too long for TCP", frame.len()))?;
+        let mut buf = Vec::with_capacity(frame.len() + 2);
+        buf.extend_from_slice(&len.to_be_bytes());
+        buf.extend_from_slice(frame);
+        self.inner.write_all(&buf)?;
+        self.inner.flush()?;
+        Ok(())
+    }
+
+    pub fn write_message(&mut self, packet: &DnsPacket) -> Result<()> {
+        let mut frame = Vec::new();
+        packet.clone().write(&mut PacketWriter::new(&mut frame))?;
+        self.write_frame(&frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FramedReader, FramedWriter};
+    use crate::dns::{DnsPacket, QueryType};
+    use std::io::{self, Cursor, Read};
+
+    #[test]
+    fn round_trip() {
+        let mut writer = FramedWriter::new(Vec::new());
+        for (id, name) in [(1, "example.com"), (2, "www.example.org")] {
+            let query = DnsPacket::query(name, QueryType::A).id(id).build();
+            writer.write_message(&query).unwrap();
+        }
+        let stream = writer.into_inner();
+        assert_eq!(&stream[..2], &[0, 29]);
+
+        let mut reader = FramedReader::new(Cursor::new(stream.clone()));
+        let first = reader.read_message().unwrap().unwrap();
+        assert_eq!(first.header.id, 1);
+        let second = reader.read_message().unwrap().unwrap();
+        assert_eq!(second.questions[0].name, "www.example.org");
+        assert!(reader.read_message().unwrap().is_none());
+
+        // a message cut off in the middle
+        let mut reader = FramedReader::new(Cursor::new(&stream[..10]));
+        assert!(reader.read_message().is_err());
+    }
+
+    /// Hands out `data` in pieces of at most `chunk` bytes, and times out
+    /// whenever it has nothing left.
+    struct Stalling {
+        data: Vec<u8>,
+        chunk: usize,
+    }
+
+    impl Read for Stalling {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.data.is_empty() {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let n = buf.len().min(self.chunk).min(self.data.len());
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data.drain(..n);
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn timeouts() {
+        let mut writer = FramedWriter::new(Vec::new());
+        let query = DnsPacket::query("example.com", QueryType::A).build();
+        writer.write_message(&query).unwrap();
+        let stream = writer.into_inner();
+
+        // between messages a timeout is just that
+        let mut reader = FramedReader::new(Stalling {
+            data: stream.clone(),
+            chunk: 1,
+        });
+        assert!(reader.read_message().unwrap().is_some());
+        let e = reader.read_message().unwrap_err();
+        let e = e.downcast_ref::<io::Error>().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+
+        // after the length, or half of it, the message is lost
+        for cut in [1, 2, 10] {
+            let mut reader = FramedReader::new(Stalling {
+                data: stream[..cut].to_vec(),
+                chunk: 1,
+            });
+            let e = reader.read_message().unwrap_err();
+            let e = e.downcast_ref::<io::Error>().unwrap();
+            assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof, "cut at {}", cut);
+        }
+    }
+}
@@ -0,0 +1,354 @@
+//! In-memory DNS hierarchy for tests.
+//!
+//! `FakeNetwork` implements `Transport` by answering queries from zones
+//! held in memory, so resolution through root, TLD and authoritative
+//! servers can run deterministically without touching the network. Zones
+//! answer like an authoritative server would: referrals with glue below
+//! delegations, CNAMEs followed inside the zone, and NXDOMAIN or NODATA
+//! with the zone's SOA. Available to other crates with the `testing`
+//! feature.
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::dns::{is_subdomain, DnsPacket, DnsQuestion, DnsRecord, ResultCode};
+use crate::transport::Transport;
+use crate::zone::ZoneParser;
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+/// A zone a fake server is authoritative for.
+#[derive(Clone, Debug)]
+pub struct Zone {
+    pub apex: String,
+    pub records: Vec<DnsRecord>,
+}
+
+impl Zone {
+    pub fn new(apex: impl Into<String>, records: Vec<DnsRecord>) -> Zone {
+        Zone {
+            apex: apex.into(),
+            records,
+        }
+    }
+
+    /// build a zone from master file text, relative names are completed
+    /// with `apex`. Lines are trimmed so fixtures can be indented, which
+    /// means every record has to name its owner. Panics on syntax errors,
+    /// it's meant for fixtures.
+    pub fn parse(apex: &str, text: &str) -> Zone {
+        let text: String = text.lines().map(|l| format!("{}\n", l.trim())).collect();
+        let records = ZoneParser::new()
+            .origin(if apex.is_empty() { "." } else { apex })
+            .default_ttl(3600)
+            .parse_str(&text)
+            .unwrap_or_else(|e| panic!("invalid zone {:?}: {}", apex, e));
+        Zone::new(apex, records)
+    }
+
+    fn soa(&self) -> Option<&DnsRecord> {
+        self.records
+            .iter()
+            .find(|r| matches!(r, DnsRecord::SOA { .. }) && r.domain() == self.apex)
+    }
+
+    /// the highest delegation between the apex and `qname`
+    fn delegation(&self, qname: &str) -> Option<&str> {
+        self.records
+            .iter()
+            .filter(|r| matches!(r, DnsRecord::NS { .. }))
+            .map(|r| r.domain())
+            .filter(|owner| *owner != self.apex && is_subdomain(qname, owner))
+            .min_by_key(|owner| owner.len())
+    }
+
+    /// address records for `host` held by this zone
+    fn addresses<'a>(&'a self, host: &'a str) -> impl Iterator<Item = &'a DnsRecord> {
+        self.records.iter().filter(move |r| {
+            matches!(r, DnsRecord::A { .. } | DnsRecord::AAAA { .. }) && r.domain() == host
+        })
+    }
+
+    fn answer(&self, query: &DnsPacket, question: &DnsQuestion) -> DnsPacket {
+        let response = DnsPacket::response_to(query);
+
+        if let Some(cut) = self.delegation(&question.name) {
+            let ns: Vec<DnsRecord> = self
+                .records
+                .iter()
+                .filter(|r| matches!(r, DnsRecord::NS { .. }) && r.domain() == cut)
+                .cloned()
+                .collect();
+            let glue: Vec<DnsRecord> = ns
+                .iter()
+                .filter_map(|r| match r {
+                    DnsRecord::NS { host, .. } => Some(host.as_str()),
+                    _ => None,
+                })
+                .flat_map(|host| self.addresses(host).cloned())
+                .collect();
+            return response.authorities(ns).additionals(glue).build();
+        }
+
+        let mut answers = Vec::new();
+        let mut name = question.name.clone();
+        // follow CNAMEs as long as they stay inside this zone
+        for _ in 0..8 {
+            let at_name: Vec<&DnsRecord> =
+                self.records.iter().filter(|r| r.domain() == name).collect();
+            let matching: Vec<DnsRecord> = at_name
+                .iter()
+                .filter(|r| r.qtype() == question.qtype)
+                .map(|r| (*r).clone())
+                .collect();
+            if !matching.is_empty() {
+                // name server addresses go along with NS answers, which is
+                // what priming relies on
+                let glue: Vec<DnsRecord> = matching
+                    .iter()
+                    .filter_map(|r| match r {
+                        DnsRecord::NS { host, .. } => Some(host.as_str()),
+                        _ => None,
+                    })
+                    .flat_map(|host| self.addresses(host).cloned())
+                    .collect();
+                answers.extend(matching);
+                return response
+                    .authoritative(true)
+                    .answers(answers)
+                    .additionals(glue)
+                    .build();
+            }
+
+            let cname = at_name.iter().find_map(|r| match r {
+                DnsRecord::CNAME { host, .. } => Some(host.clone()),
+                _ => None,
+            });
+            if let Some(target) = cname {
+                answers.extend(at_name.into_iter().cloned());
+                if !is_subdomain(&target, &self.apex) {
+                    break;
+                }
+                name = target;
+                continue;
+            }
+
+            if !answers.is_empty() {
+                break;
+            }
+
+            let exists = self.records.iter().any(|r| is_subdomain(r.domain(), &name));
+            let rcode = if exists {
+                ResultCode::NOERROR
+            } else {
+                ResultCode::NXDOMAIN
+            };
+            return response
+                .authoritative(true)
+                .rcode(rcode)
+                .authorities(self.soa().cloned())
+                .build();
+        }
+
+        response.authoritative(true).answers(answers).build()
+    }
+}
+
+/// A set of fake name servers, addressed by IP, each serving some zones.
+/// Queries to addresses without a server fail like a timeout would.
+#[derive(Debug, Default)]
+pub struct FakeNetwork {
+    servers: HashMap<IpAddr, Vec<Zone>>,
+    /// servers whose responses over UDP are truncated
+    truncating: HashSet<IpAddr>,
+    /// servers that echo the question in lowercase
+    lowercasing: HashSet<IpAddr>,
+    log: Mutex<Vec<(SocketAddr, DnsQuestion)>>,
+    tcp_log: Mutex<Vec<(SocketAddr, DnsQuestion)>>,
+}
+
+impl FakeNetwork {
+    pub fn new() -> FakeNetwork {
+        FakeNetwork::default()
+    }
+
+    /// let the server at `addr` serve `zone` as well
+    pub fn server(mut self, addr: &str, zone: Zone) -> Self {
+        let addr: IpAddr = addr.parse().expect("invalid server address");
+        self.servers.entry(addr).or_default().push(zone);
+        self
+    }
+
+    /// let the server at `addr` answer UDP queries with truncated, empty
+    /// responses, so that only TCP gets the whole answer
+    pub fn truncating(mut self, addr: &str) -> Self {
+        let addr: IpAddr = addr.parse().expect("invalid server address");
+        self.truncating.insert(addr);
+        self
+    }
+
+    /// let the server at `addr` lose the letter case of questions, like
+    /// some broken servers do
+    pub fn lowercasing(mut self, addr: &str) -> Self {
+        let addr: IpAddr = addr.parse().expect("invalid server address");
+        self.lowercasing.insert(addr);
+        self
+    }
+
+    /// every query received so far, in order
+    pub fn queries(&self) -> Vec<(SocketAddr, DnsQuestion)> {
+        self.log.lock().unwrap().clone()
+    }
+
+    pub fn query_count(&self) -> usize {
+        self.log.lock().unwrap().len()
+    }
+
+    /// queries sent to a particular server
+    pub fn queries_to(&self, addr: &str) -> usize {
+        let addr: IpAddr = addr.parse().expect("invalid server address");
+        self.log
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(server, _)| server.ip() == addr)
+            .count()
+    }
+
+    /// queries received over TCP
+    pub fn tcp_queries(&self) -> Vec<(SocketAddr, DnsQuestion)> {
+        self.tcp_log.lock().unwrap().clone()
+    }
+
+    pub fn clear_log(&self) {
+        self.log.lock().unwrap().clear();
+        self.tcp_log.lock().unwrap().clear();
+    }
+
+    fn answer(&self, query: &DnsPacket, server: SocketAddr) -> Result<DnsPacket> {
+        let question = query.questions.first().ok_or("query without question")?;
+        self.log.lock().unwrap().push((server, question.clone()));
+
+        let Some(zones) = self.servers.get(&server.ip()) else {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "no response").into());
+        };
+
+        // names are matched regardless of case, the question is echoed
+        // as it was asked
+        let lowered = DnsQuestion {
+            name: question.name.to_ascii_lowercase(),
+            ..question.clone()
+        };
+
+        // the closest enclosing zone served by this server answers
+        let zone = zones
+            .iter()
+            .filter(|z| is_subdomain(&lowered.name, &z.apex))
+            .max_by_key(|z| z.apex.len());
+
+        let mut response = match zone {
+            Some(zone) => zone.answer(query, &lowered),
+            None => DnsPacket::response_to(query)
+                .rcode(ResultCode::REFUSED)
+                .build(),
+        };
+        if self.lowercasing.contains(&server.ip()) {
+            for question in &mut response.questions {
+                question.name.make_ascii_lowercase();
+            }
+        }
+        Ok(response)
+    }
+}
+
+impl Transport for FakeNetwork {
+    fn query(&self, query: &DnsPacket, server: SocketAddr, _: Duration) -> Result<DnsPacket> {
+        let response = self.answer(query, server)?;
+        if self.truncating.contains(&server.ip()) {
+            return Ok(DnsPacket::response_to(query).truncated(true).build());
+        }
+        Ok(response)
+    }
+
+    fn query_tcp(&self, query: &DnsPacket, server: SocketAddr, _: Duration) -> Result<DnsPacket> {
+        if let Some(question) = query.questions.first() {
+            self.tcp_log
+                .lock()
+                .unwrap()
+                .push((server, question.clone()));
+        }
+        self.answer(query, server)
+    }
+}
+
+/// Root, `com` and `example.com` servers, with `www.example.com` at
+/// 192.0.2.80, an in-zone CNAME and a glueless delegation for
+/// `example.org` whose name server lives under `example.com`. CNAMEs
+/// lead from one zone into the other, and `loop1.example.com` loops.
+pub fn example_hierarchy() -> FakeNetwork {
+    FakeNetwork::new()
+        .server(
+            "198.41.0.4",
+            Zone::parse(
+                "",
+                "@ SOA a.root-servers.net. nstld.verisign-grs.com. 1 1800 900 604800 86400
+                 @ NS a.root-servers.net.
+                 a.root-servers.net. A 198.41.0.4
+                 com. NS a.gtld-servers.net.
+                 org. NS a0.org-servers.net.
+                 a.gtld-servers.net. A 192.5.6.30
+                 a0.org-servers.net. A 199.19.56.1",
+            ),
+        )
+        .server(
+            "192.5.6.30",
+            Zone::parse(
+                "com",
+                "@ SOA a.gtld-servers.net. nstld.verisign-grs.com. 1 1800 900 604800 86400
+                 @ NS a.gtld-servers.net.
+                 example NS ns1.example
+                 ns1.example A 192.0.2.53",
+            ),
+        )
+        .server(
+            "199.19.56.1",
+            Zone::parse(
+                "org",
+                "@ SOA a0.org-servers.net. noc.afilias-nst.info. 1 1800 900 604800 86400
+                 @ NS a0.org-servers.net.
+                 example NS ns2.example.com.",
+            ),
+        )
+        .server(
+            "192.0.2.53",
+            Zone::parse(
+                "example.com",
+                "@ SOA ns1 hostmaster 1 7200 3600 1209600 300
+                 @ NS ns1
+                 @ MX 10 mail
+                 ns1 A 192.0.2.53
+                 ns2 A 192.0.2.54
+                 www A 192.0.2.80
+                 mail A 192.0.2.25
+                 alias CNAME www
+                 ext CNAME alias.example.org.
+                 loop1 CNAME loop2
+                 loop2 CNAME loop3.example.org.
+                 deep.sub TXT \"hello\"",
+            ),
+        )
+        .server(
+            "192.0.2.54",
+            Zone::parse(
+                "example.org",
+                "@ SOA ns2.example.com. hostmaster.example.com. 1 7200 3600 1209600 300
+                 @ NS ns2.example.com.
+                 www A 192.0.2.81
+                 alias CNAME www
+                 loop3 CNAME loop1.example.com.",
+            ),
+        )
+}
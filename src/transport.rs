@@ -0,0 +1,236 @@
+//! How the resolver exchanges messages with name servers.
+//!
+//! Responses are only accepted from the server a query was sent to, with
+//! the query's ID and echoing its question (RFC 5452). Anything else may
+//! be an attempt at spoofing and is dropped, while waiting on for the
+//! real response.
+use std::io::{self, Cursor};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket};
+use std::time::{Duration, Instant};
+
+use rand::{thread_rng, Rng};
+
+use crate::dns::DnsPacket;
+use crate::packet::{PacketReader, PacketWriter};
+use crate::tcp::{FramedReader, FramedWriter};
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+/// Sends a query to a name server and returns its response, giving up
+/// after `timeout`.
+pub trait Transport: Send + Sync {
+    fn query(&self, query: &DnsPacket, server: SocketAddr, timeout: Duration) -> Result<DnsPacket>;
+
+    /// the same over TCP, used when the response to `query` came back
+    /// truncated
+    fn query_tcp(
+        &self,
+        query: &DnsPacket,
+        server: SocketAddr,
+        timeout: Duration,
+    ) -> Result<DnsPacket>;
+}
+
+/// attempts at binding a random port before leaving it to the system
+const BIND_ATTEMPTS: usize = 16;
+
+/// whether `response` answers `query`: a response with the same ID that
+/// echoes the question
+pub fn is_response_to(query: &DnsPacket, response: &DnsPacket) -> bool {
+    response.header.qr
+        && response.header.id == query.header.id
+        && response.questions.len() == query.questions.len()
+        && response
+            .questions
+            .iter()
+            .zip(&query.questions)
+            .all(|(r, q)| {
+                r.name.eq_ignore_ascii_case(&q.name) && r.qtype == q.qtype && r.qclass == q.qclass
+            })
+}
+
+/// a UDP socket for talking to `server`, on a random port so that the
+/// port can't be guessed by a spoofer
+pub(crate) fn bind_random(server: SocketAddr) -> io::Result<UdpSocket> {
+    let ip = match server {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let mut rng = thread_rng();
+    (0..BIND_ATTEMPTS)
+        .find_map(|_| UdpSocket::bind(SocketAddr::new(ip, rng.gen_range(1024..=65535))).ok())
+        .map_or_else(|| UdpSocket::bind(SocketAddr::new(ip, 0)), Ok)
+}
+
+/// Plain DNS over UDP, one fresh socket per query, and TCP for responses
+/// that don't fit into a datagram.
+#[derive(Clone, Debug, Default)]
+pub struct UdpTransport;
+
+impl UdpTransport {
+    pub fn new() -> UdpTransport {
+        UdpTransport
+    }
+}
+
+impl Transport for UdpTransport {
+    fn query(&self, query: &DnsPacket, server: SocketAddr, timeout: Duration) -> Result<DnsPacket> {
+        let socket = bind_random(server)?;
+        socket.set_write_timeout(Some(timeout))?;
+
+        let mut w = Vec::with_capacity(64);
+        let mut req_buffer = PacketWriter::new(&mut w);
+        query.clone().write(&mut req_buffer)?;
+        socket.send_to(&w, server)?;
+
+        let deadline = Instant::now() + timeout;
+        let mut rv = vec![0; 512];
+        loop {
+            // would block the execution because the data is
+            // not ready to be read, so wait no longer than
+            // what is left of the timeout
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "query timed out").into());
+            }
+            socket.set_read_timeout(Some(left))?;
+
+            let (len, src) = socket.recv_from(&mut rv)?;
+            if src != server {
+                log::debug!("dropping datagram from {}, expected {}", src, server);
+                continue;
+            }
+            let mut buffer = PacketReader::new(Cursor::new(&rv[..len]));
+            match DnsPacket::from_buffer(&mut buffer) {
+                Ok(packet) if is_response_to(query, &packet) => return Ok(packet),
+                Ok(packet) => log::debug!(
+                    "dropping response from {} with id {} not matching the query",
+                    src,
+                    packet.header.id
+                ),
+                Err(e) => log::debug!("dropping malformed response from {}: {}", src, e),
+            }
+        }
+    }
+
+    fn query_tcp(
+        &self,
+        query: &DnsPacket,
+        server: SocketAddr,
+        timeout: Duration,
+    ) -> Result<DnsPacket> {
+        TcpTransport.query_tcp(query, server, timeout)
+    }
+}
+
+/// DNS over TCP, one connection per query.
+#[derive(Clone, Debug, Default)]
+pub struct TcpTransport;
+
+impl TcpTransport {
+    pub fn new() -> TcpTransport {
+        TcpTransport
+    }
+}
+
+impl Transport for TcpTransport {
+    fn query(&self, query: &DnsPacket, server: SocketAddr, timeout: Duration) -> Result<DnsPacket> {
+        self.query_tcp(query, server, timeout)
+    }
+
+    fn query_tcp(
+        &self,
+        query: &DnsPacket,
+        server: SocketAddr,
+        timeout: Duration,
+    ) -> Result<DnsPacket> {
+        let stream = TcpStream::connect_timeout(&server, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        stream.set_nodelay(true)?;
+
+        FramedWriter::new(&stream).write_message(query)?;
+        let mut reader = FramedReader::new(&stream);
+        loop {
+            let response = reader
+                .read_message()?
+                .ok_or("connection closed without a response")?;
+            if is_response_to(query, &response) {
+                return Ok(response);
+            }
+            log::debug!(
+                "dropping response from {} with id {} not matching the query",
+                server,
+                response.header.id
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Transport, UdpTransport};
+    use crate::dns::{DnsPacket, QueryType};
+    use crate::packet::{PacketReader, PacketWriter};
+    use crate::pool::PooledTransport;
+    use std::io::Cursor;
+    use std::net::UdpSocket;
+    use std::thread;
+    use std::time::Duration;
+
+    fn send(socket: &UdpSocket, packet: DnsPacket, to: std::net::SocketAddr) {
+        let mut w = Vec::new();
+        packet
+            .clone()
+            .write(&mut PacketWriter::new(&mut w))
+            .unwrap();
+        socket.send_to(&w, to).unwrap();
+    }
+
+    #[test]
+    fn spoofed_responses_are_dropped() {
+        let transports: [Box<dyn Transport>; 2] = [
+            Box::new(UdpTransport::new()),
+            Box::new(PooledTransport::default()),
+        ];
+        for transport in transports {
+            let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+            let addr = server.local_addr().unwrap();
+            let query = DnsPacket::query("www.example.com", QueryType::A)
+                .id(40000)
+                .build();
+
+            let spoofer = thread::spawn(move || {
+                let mut buf = vec![0; 512];
+                let (len, client) = server.recv_from(&mut buf).unwrap();
+                buf.truncate(len);
+                let query =
+                    DnsPacket::from_buffer(&mut PacketReader::new(Cursor::new(buf))).unwrap();
+
+                // from another port, with another id, for another name
+                let other = UdpSocket::bind("127.0.0.1:0").unwrap();
+                send(&other, DnsPacket::response_to(&query).build(), client);
+                send(
+                    &server,
+                    DnsPacket::response_to(&query).id(1).build(),
+                    client,
+                );
+                let wrong = DnsPacket::query("www.example.org", QueryType::A)
+                    .id(40000)
+                    .build();
+                send(&server, DnsPacket::response_to(&wrong).build(), client);
+
+                let real = DnsPacket::response_to(&query).authoritative(true).build();
+                send(&server, real, client);
+            });
+
+            let response = transport
+                .query(&query, addr, Duration::from_secs(2))
+                .unwrap();
+            assert!(response.header.aa);
+            assert_eq!(response.questions[0].name, "www.example.com");
+            spoofer.join().unwrap();
+        }
+    }
+}
@@ -0,0 +1,919 @@
+//! Parser for RFC 1035 master (zone) files.
+//!
+//! Supports the `$ORIGIN`, `$TTL` and `$INCLUDE` directives, `@`, relative
+//! names, owner/TTL/class inheritance, parenthesised multi-line records,
+//! comments and escaped character-strings. Names are returned in the same
+//! form `PacketReader::read_name` produces: lowercase, without the trailing
+//! dot, and the root as an empty string.
+use std::{
+    fmt, fs,
+    net::{Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+};
+
+use crate::dns::{DnsRecord, QueryType};
+
+/// maximum nesting of `$INCLUDE` directives, guards against include cycles
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// An error found while parsing a zone file, with the position it was found at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ZoneError {
+    pub file: Option<PathBuf>,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ZoneError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(ref file) = self.file {
+            write!(f, "{}:", file.display())?;
+        }
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl std::error::Error for ZoneError {}
+
+type Result<T> = std::result::Result<T, ZoneError>;
+
+#[derive(Debug, Clone)]
+struct Token {
+    text: String,
+    quoted: bool,
+    line: usize,
+    column: usize,
+}
+
+/// a logical entry, which may span several physical lines inside parentheses
+#[derive(Debug)]
+struct Entry {
+    tokens: Vec<Token>,
+    // the entry started with whitespace, so the owner is inherited
+    blank_owner: bool,
+}
+
+fn err<T>(line: usize, column: usize, message: impl Into<String>) -> Result<T> {
+    Err(ZoneError {
+        file: None,
+        line,
+        column,
+        message: message.into(),
+    })
+}
+
+fn tok_err<T>(token: &Token, message: impl Into<String>) -> Result<T> {
+    err(token.line, token.column, message)
+}
+
+fn is_delimiter(c: char) -> bool {
+    matches!(c, ' ' | '\t' | '\r' | '\n' | ';' | '(' | ')' | '"')
+}
+
+/// split the input into logical entries
+fn tokenize(text: &str) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut tokens: Vec<Token> = Vec::new();
+    // whether the current physical line started with whitespace
+    let mut line_blank = false;
+    // decided by the first token of every entry
+    let mut blank_owner = false;
+    // position of the opening parenthesis, if inside one
+    let mut paren: Option<(usize, usize)> = None;
+
+    let mut line = 1;
+    let mut column = 0;
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        column += 1;
+        let (tok_line, tok_column) = (line, column);
+
+        match c {
+            '\n' => {
+                if paren.is_none() && !tokens.is_empty() {
+                    entries.push(Entry {
+                        tokens: std::mem::take(&mut tokens),
+                        blank_owner,
+                    });
+                }
+                line += 1;
+                column = 0;
+                line_blank = false;
+            }
+            ' ' | '\t' | '\r' => {
+                if column == 1 {
+                    line_blank = true;
+                }
+            }
+            ';' => {
+                while let Some(&c) = chars.peek() {
+                    if c == '\n' {
+                        break;
+                    }
+                    chars.next();
+                }
+            }
+            '(' => {
+                if paren.is_some() {
+                    return err(tok_line, tok_column, "nested parentheses");
+                }
+                paren = Some((tok_line, tok_column));
+            }
+            ')' => {
+                if paren.take().is_none() {
+                    return err(tok_line, tok_column, "unbalanced ')'");
+                }
+            }
+            _ => {
+                let quoted = c == '"';
+                let mut s = String::new();
+                if quoted {
+                    loop {
+                        match chars.next() {
+                            Some('"') => {
+                                column += 1;
+                                break;
+                            }
+                            Some('\n') | None => {
+                                return err(tok_line, tok_column, "unterminated quoted string");
+                            }
+                            Some('\\') => {
+                                column += 2;
+                                s.push('\\');
+                                match chars.next() {
+                                    Some('\n') | None => {
+                                        return err(tok_line, tok_column, "dangling escape");
+                                    }
+                                    Some(e) => s.push(e),
+                                }
+                            }
+                            Some(c) => {
+                                column += 1;
+                                s.push(c);
+                            }
+                        }
+                    }
+                } else {
+                    let mut c = c;
+                    loop {
+                        s.push(c);
+                        if c == '\\' {
+                            // the escaped character belongs to the token whatever it is
+                            match chars.next() {
+                                Some('\n') | None => {
+                                    return err(tok_line, tok_column, "dangling escape");
+                                }
+                                Some(e) => {
+                                    column += 1;
+                                    s.push(e);
+                                }
+                            }
+                        }
+                        match chars.peek() {
+                            Some(&n) if !is_delimiter(n) => {
+                                c = n;
+                                chars.next();
+                                column += 1;
+                            }
+                            _ => break,
+                        }
+                    }
+                }
+
+                if tokens.is_empty() {
+                    blank_owner = line_blank;
+                }
+                tokens.push(Token {
+                    text: s,
+                    quoted,
+                    line: tok_line,
+                    column: tok_column,
+                });
+            }
+        }
+    }
+
+    if let Some((l, c)) = paren {
+        return err(l, c, "unbalanced '('");
+    }
+    if !tokens.is_empty() {
+        entries.push(Entry {
+            tokens,
+            blank_owner,
+        });
+    }
+    Ok(entries)
+}
+
+/// resolve `\X` and `\DDD` escapes into raw bytes
+fn unescape(token: &Token) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(token.text.len());
+    let mut chars = token.text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut b = [0u8; 4];
+            out.extend_from_slice(c.encode_utf8(&mut b).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some(d) if d.is_ascii_digit() => {
+                let digits: String = std::iter::once(d).chain(chars.by_ref().take(2)).collect();
+                match digits.parse::<u8>() {
+                    Ok(v) if digits.len() == 3 && digits.chars().all(|c| c.is_ascii_digit()) => {
+                        out.push(v)
+                    }
+                    _ => return tok_err(token, format!("invalid escape \\{}", digits)),
+                }
+            }
+            Some(e) => {
+                let mut b = [0u8; 4];
+                out.extend_from_slice(e.encode_utf8(&mut b).as_bytes());
+            }
+            None => return tok_err(token, "dangling escape"),
+        }
+    }
+    Ok(out)
+}
+
+/// parse a TTL, either plain seconds or BIND style units such as `1h30m`
+fn parse_ttl(text: &str) -> Option<u32> {
+    if text.is_empty() {
+        return None;
+    }
+    if let Ok(v) = text.parse::<u32>() {
+        return Some(v);
+    }
+
+    let mut total: u64 = 0;
+    let mut value: Option<u64> = None;
+    for c in text.chars() {
+        if let Some(d) = c.to_digit(10) {
+            value = Some(value.unwrap_or(0) * 10 + d as u64);
+            if value > Some(u32::MAX as u64) {
+                return None;
+            }
+            continue;
+        }
+        let unit = match c.to_ascii_lowercase() {
+            'w' => 604800,
+            'd' => 86400,
+            'h' => 3600,
+            'm' => 60,
+            's' => 1,
+            _ => return None,
+        };
+        total += value.take()? * unit;
+    }
+    if value.is_some() {
+        // a trailing number without unit
+        return None;
+    }
+    u32::try_from(total).ok()
+}
+
+fn parse_class(text: &str) -> Option<u16> {
+    match text.to_ascii_uppercase().as_str() {
+        "IN" => Some(1),
+        "CS" => Some(2),
+        "CH" => Some(3),
+        "HS" => Some(4),
+        s => s.strip_prefix("CLASS").and_then(|n| n.parse().ok()),
+    }
+}
+
+fn parse_type(text: &str) -> Option<QueryType> {
+    match text.to_ascii_uppercase().as_str() {
+        "A" => Some(QueryType::A),
+        "NS" => Some(QueryType::NS),
+        "CNAME" => Some(QueryType::CNAME),
+        "SOA" => Some(QueryType::SOA),
+        "MX" => Some(QueryType::MX),
+        "TXT" => Some(QueryType::TXT),
+        "AAAA" => Some(QueryType::AAAA),
+        s => s
+            .strip_prefix("TYPE")
+            .and_then(|n| n.parse().ok())
+            .map(QueryType::from_num),
+    }
+}
+
+/// Parser state that is carried from one entry to the next.
+struct State {
+    origin: Option<String>,
+    default_ttl: Option<u32>,
+    last_ttl: Option<u32>,
+    last_owner: Option<String>,
+    last_class: u16,
+}
+
+/// Parses zone files into `DnsRecord`s.
+///
+/// ```
+/// use dns::zone::ZoneParser;
+///
+/// let records = ZoneParser::new()
+///     .origin("example.com")
+///     .parse_str("$TTL 1h\n@ NS ns1\nns1 A 192.0.2.1\n")
+///     .unwrap();
+/// assert_eq!(records.len(), 2);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ZoneParser {
+    origin: Option<String>,
+    default_ttl: Option<u32>,
+    base_dir: Option<PathBuf>,
+}
+
+impl ZoneParser {
+    pub fn new() -> ZoneParser {
+        ZoneParser::default()
+    }
+
+    /// initial origin, used until the first `$ORIGIN` directive
+    pub fn origin(mut self, origin: impl AsRef<str>) -> Self {
+        self.origin = Some(normalize_absolute(origin.as_ref()));
+        self
+    }
+
+    /// TTL used until the first `$TTL` directive or explicit TTL
+    pub fn default_ttl(mut self, ttl: u32) -> Self {
+        self.default_ttl = Some(ttl);
+        self
+    }
+
+    /// directory relative `$INCLUDE` paths are resolved against, defaults to
+    /// the directory of the file being parsed or the current directory
+    pub fn base_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.base_dir = Some(dir.into());
+        self
+    }
+
+    /// parse zone file contents
+    pub fn parse_str(&self, text: &str) -> Result<Vec<DnsRecord>> {
+        let mut state = self.initial_state();
+        let base_dir = self.base_dir.clone().unwrap_or_default();
+        let mut records = Vec::new();
+        self.parse_text(text, &base_dir, &mut state, &mut records, 0)?;
+        Ok(records)
+    }
+
+    /// Parse a single record given its parts, with the RDATA in
+    /// presentation format. Nothing but the RDATA is tokenized and
+    /// directives aren't interpreted, so text from untrusted sources can't
+    /// smuggle in other entries or `$INCLUDE` files.
+    pub fn parse_rdata(
+        &self,
+        owner: &str,
+        ttl: u32,
+        rtype: &str,
+        rdata: &str,
+    ) -> Result<DnsRecord> {
+        let token = |text: &str| Token {
+            text: text.to_owned(),
+            quoted: false,
+            line: 1,
+            column: 1,
+        };
+        let mut entries = tokenize(rdata)?;
+        if entries.len() > 1 {
+            return err(1, 1, "RDATA must be a single entry");
+        }
+
+        let mut tokens = vec![token(owner), token(&ttl.to_string()), token(rtype)];
+        if let Some(entry) = entries.pop() {
+            tokens.extend(entry.tokens);
+        }
+        let entry = Entry {
+            tokens,
+            blank_owner: false,
+        };
+        parse_record(&entry, &mut self.initial_state())
+    }
+
+    /// read and parse a zone file from disk
+    pub fn parse_file(&self, path: impl AsRef<Path>) -> Result<Vec<DnsRecord>> {
+        let mut state = self.initial_state();
+        let mut records = Vec::new();
+        self.parse_path(path.as_ref(), &mut state, &mut records, 0)?;
+        Ok(records)
+    }
+
+    fn initial_state(&self) -> State {
+        State {
+            origin: self.origin.clone(),
+            default_ttl: self.default_ttl,
+            last_ttl: None,
+            last_owner: None,
+            last_class: 1,
+        }
+    }
+
+    fn parse_path(
+        &self,
+        path: &Path,
+        state: &mut State,
+        records: &mut Vec<DnsRecord>,
+        depth: usize,
+    ) -> Result<()> {
+        let text = fs::read_to_string(path).map_err(|e| ZoneError {
+            file: Some(path.to_owned()),
+            line: 0,
+            column: 0,
+            message: format!("failed to read zone file: {}", e),
+        })?;
+        let base_dir = match self.base_dir {
+            Some(ref dir) => dir.clone(),
+            None => path.parent().map(Path::to_owned).unwrap_or_default(),
+        };
+
+        self.parse_text(&text, &base_dir, state, records, depth)
+            .map_err(|mut e| {
+                // errors from nested includes already name their file
+                if e.file.is_none() {
+                    e.file = Some(path.to_owned());
+                }
+                e
+            })
+    }
+
+    fn parse_text(
+        &self,
+        text: &str,
+        base_dir: &Path,
+        state: &mut State,
+        records: &mut Vec<DnsRecord>,
+        depth: usize,
+    ) -> Result<()> {
+        for entry in tokenize(text)? {
+            let first = &entry.tokens[0];
+            if !entry.blank_owner && first.text.starts_with('$') && !first.quoted {
+                self.directive(&entry, base_dir, state, records, depth)?;
+            } else {
+                records.push(parse_record(&entry, state)?);
+            }
+        }
+        Ok(())
+    }
+
+    fn directive(
+        &self,
+        entry: &Entry,
+        base_dir: &Path,
+        state: &mut State,
+        records: &mut Vec<DnsRecord>,
+        depth: usize,
+    ) -> Result<()> {
+        let directive = &entry.tokens[0];
+        let args = &entry.tokens[1..];
+
+        match directive.text.to_ascii_uppercase().as_str() {
+            "$ORIGIN" => {
+                let [name] = args else {
+                    return tok_err(directive, "$ORIGIN takes exactly one name");
+                };
+                state.origin = Some(parse_name(name, state.origin.as_deref())?);
+            }
+            "$TTL" => {
+                let [ttl] = args else {
+                    return tok_err(directive, "$TTL takes exactly one TTL");
+                };
+                match parse_ttl(&ttl.text) {
+                    Some(v) => state.default_ttl = Some(v),
+                    None => return tok_err(ttl, format!("invalid TTL '{}'", ttl.text)),
+                }
+            }
+            "$INCLUDE" => {
+                let (file, origin) = match args {
+                    [file] => (file, None),
+                    [file, origin] => (file, Some(origin)),
+                    _ => {
+                        return tok_err(directive, "$INCLUDE takes a file name and optional origin")
+                    }
+                };
+                if depth >= MAX_INCLUDE_DEPTH {
+                    return tok_err(directive, "$INCLUDE nested too deeply");
+                }
+
+                let bytes = unescape(file)?;
+                let path = base_dir.join(String::from_utf8_lossy(&bytes).as_ref());
+
+                // the included file gets its own origin, the including file
+                // keeps its origin and owner once the include is done
+                let mut nested = State {
+                    origin: match origin {
+                        Some(o) => Some(parse_name(o, state.origin.as_deref())?),
+                        None => state.origin.clone(),
+                    },
+                    default_ttl: state.default_ttl,
+                    last_ttl: state.last_ttl,
+                    last_owner: state.last_owner.clone(),
+                    last_class: state.last_class,
+                };
+                if !path.is_file() {
+                    return tok_err(file, format!("cannot include {}", path.display()));
+                }
+                self.parse_path(&path, &mut nested, records, depth + 1)?;
+                state.default_ttl = nested.default_ttl;
+                state.last_ttl = nested.last_ttl;
+            }
+            other => return tok_err(directive, format!("unknown directive {}", other)),
+        }
+        Ok(())
+    }
+}
+
+/// turn a user supplied absolute name into the crate's internal form
+fn normalize_absolute(name: &str) -> String {
+    name.trim_end_matches('.').to_lowercase()
+}
+
+/// parse a domain name token, relative names are completed with the origin
+fn parse_name(token: &Token, origin: Option<&str>) -> Result<String> {
+    let text = token.text.as_str();
+    if text == "@" {
+        return match origin {
+            Some(o) => Ok(o.to_owned()),
+            None => tok_err(token, "'@' used without an origin"),
+        };
+    }
+    if text == "." {
+        return Ok(String::new());
+    }
+
+    // split on unescaped dots, then resolve escapes in each label
+    let mut labels = Vec::new();
+    let mut current = String::new();
+    let mut chars = text.chars();
+    let mut absolute = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                current.push(c);
+                if let Some(e) = chars.next() {
+                    current.push(e);
+                }
+            }
+            '.' => {
+                if current.is_empty() {
+                    return tok_err(token, format!("empty label in '{}'", text));
+                }
+                labels.push(std::mem::take(&mut current));
+                absolute = chars.as_str().is_empty();
+            }
+            _ => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        labels.push(current);
+    }
+
+    let mut decoded = Vec::with_capacity(labels.len());
+    for label in labels {
+        let bytes = unescape(&Token {
+            text: label,
+            ..token.clone()
+        })?;
+        if bytes.contains(&b'.') {
+            return tok_err(token, "escaped dots inside labels are not supported");
+        }
+        if bytes.len() > 63 {
+            return tok_err(token, "label longer than 63 octets");
+        }
+        decoded.push(String::from_utf8_lossy(&bytes).to_lowercase());
+    }
+
+    let mut name = decoded.join(".");
+    if !absolute {
+        match origin {
+            Some("") => {}
+            Some(o) => {
+                name.push('.');
+                name.push_str(o);
+            }
+            None => return tok_err(token, format!("relative name '{}' without an origin", text)),
+        }
+    }
+    if name.len() > 253 {
+        return tok_err(token, "name longer than 255 octets");
+    }
+    Ok(name)
+}
+
+fn parse_u16(token: &Token, what: &str) -> Result<u16> {
+    token
+        .text
+        .parse()
+        .or_else(|_| tok_err(token, format!("invalid {} '{}'", what, token.text)))
+}
+
+fn parse_u32(token: &Token, what: &str) -> Result<u32> {
+    token
+        .text
+        .parse()
+        .or_else(|_| tok_err(token, format!("invalid {} '{}'", what, token.text)))
+}
+
+fn parse_record(entry: &Entry, state: &mut State) -> Result<DnsRecord> {
+    let mut tokens = entry.tokens.iter().peekable();
+    let first = &entry.tokens[0];
+
+    let domain = if entry.blank_owner {
+        match state.last_owner {
+            Some(ref owner) => owner.clone(),
+            None => return tok_err(first, "no previous owner name to inherit"),
+        }
+    } else {
+        let owner = tokens.next().unwrap();
+        parse_name(owner, state.origin.as_deref())?
+    };
+
+    // TTL and class are both optional and may come in either order
+    let mut ttl = None;
+    let mut class = None;
+    let qtype = loop {
+        let Some(token) = tokens.next() else {
+            return tok_err(entry.tokens.last().unwrap(), "missing record type");
+        };
+        if ttl.is_none() && token.text.starts_with(|c: char| c.is_ascii_digit()) {
+            match parse_ttl(&token.text) {
+                Some(v) => ttl = Some(v),
+                None => return tok_err(token, format!("invalid TTL '{}'", token.text)),
+            }
+            continue;
+        }
+        if class.is_none() {
+            if let Some(c) = parse_class(&token.text) {
+                class = Some((c, token));
+                continue;
+            }
+        }
+        match parse_type(&token.text) {
+            Some(t) => break (t, token),
+            None => return tok_err(token, format!("unknown record type '{}'", token.text)),
+        }
+    };
+
+    let class = match class {
+        Some((c, token)) => {
+            if c != 1 {
+                return tok_err(token, "only class IN is supported");
+            }
+            c
+        }
+        None => state.last_class,
+    };
+
+    let rdata: Vec<&Token> = tokens.collect();
+    let (qtype, type_token) = qtype;
+    let origin = state.origin.as_deref();
+    let expect = |n: usize| -> Result<()> {
+        if rdata.len() != n {
+            return tok_err(
+                rdata.get(n).copied().unwrap_or(type_token),
+                format!(
+                    "{:?} record expects {} fields, got {}",
+                    qtype,
+                    n,
+                    rdata.len()
+                ),
+            );
+        }
+        Ok(())
+    };
+
+    // SOA minimum is the fallback TTL when nothing else is known
+    let soa_minimum = if qtype == QueryType::SOA && rdata.len() == 7 {
+        Some(parse_u32(rdata[6], "minimum")?)
+    } else {
+        None
+    };
+    let ttl = match ttl.or(state.default_ttl).or(state.last_ttl).or(soa_minimum) {
+        Some(v) => v,
+        None => return tok_err(first, "no TTL specified and no $TTL in effect"),
+    };
+
+    let record = match qtype {
+        QueryType::A => {
+            expect(1)?;
+            let addr = rdata[0].text.parse::<Ipv4Addr>().or_else(|_| {
+                tok_err(
+                    rdata[0],
+                    format!("invalid IPv4 address '{}'", rdata[0].text),
+                )
+            })?;
+            DnsRecord::A { domain, addr, ttl }
+        }
+        QueryType::AAAA => {
+            expect(1)?;
+            let addr = rdata[0].text.parse::<Ipv6Addr>().or_else(|_| {
+                tok_err(
+                    rdata[0],
+                    format!("invalid IPv6 address '{}'", rdata[0].text),
+                )
+            })?;
+            DnsRecord::AAAA { domain, addr, ttl }
+        }
+        QueryType::NS => {
+            expect(1)?;
+            let host = parse_name(rdata[0], origin)?;
+            DnsRecord::NS { domain, host, ttl }
+        }
+        QueryType::CNAME => {
+            expect(1)?;
+            let host = parse_name(rdata[0], origin)?;
+            DnsRecord::CNAME { domain, host, ttl }
+        }
+        QueryType::MX => {
+            expect(2)?;
+            let priority = parse_u16(rdata[0], "preference")?;
+            let host = parse_name(rdata[1], origin)?;
+            DnsRecord::MX {
+                domain,
+                priority,
+                host,
+                ttl,
+            }
+        }
+        QueryType::SOA => {
+            expect(7)?;
+            let duration = |t: &Token, what: &str| match parse_ttl(&t.text) {
+                Some(v) => Ok(v),
+                None => tok_err(t, format!("invalid {} '{}'", what, t.text)),
+            };
+            DnsRecord::SOA {
+                domain,
+                m_name: parse_name(rdata[0], origin)?,
+                r_name: parse_name(rdata[1], origin)?,
+                serial: parse_u32(rdata[2], "serial")?,
+                refresh: duration(rdata[3], "refresh")?,
+                retry: duration(rdata[4], "retry")?,
+                expire: duration(rdata[5], "expire")?,
+                minimum: duration(rdata[6], "minimum")?,
+                ttl,
+            }
+        }
+        QueryType::TXT => {
+            if rdata.is_empty() {
+                return tok_err(type_token, "TXT record needs at least one string");
+            }
+            let mut data = Vec::with_capacity(rdata.len());
+            for token in &rdata {
+                let bytes = unescape(token)?;
+                if bytes.len() > 255 {
+                    return tok_err(token, "character-string longer than 255 octets");
+                }
+                data.push(bytes);
+            }
+            DnsRecord::TXT { domain, data, ttl }
+        }
+        _ => {
+            return tok_err(
+                type_token,
+                format!("record type '{}' is not supported", type_token.text),
+            )
+        }
+    };
+
+    state.last_owner = Some(record.domain().to_owned());
+    state.last_ttl = Some(ttl);
+    state.last_class = class;
+    Ok(record)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ZoneError, ZoneParser};
+    use crate::dns::DnsRecord;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    const ZONE: &str = r#"
+$ORIGIN example.com.
+$TTL 1h
+@   IN  SOA ns1 hostmaster (
+            2024010101 ; serial
+            2h         ; refresh
+            30m        ; retry
+            2w         ; expire
+            300 )      ; minimum
+        NS  ns1
+        NS  ns2.example.net.
+        MX  10 mail
+ns1     A   192.0.2.1
+        AAAA 2001:db8::1
+www 600 IN CNAME @
+txt     TXT "hello \"world\"" semi\;colon "\065\066"
+"#;
+
+    #[test]
+    fn parse_zone() {
+        let records = ZoneParser::new().parse_str(ZONE).unwrap();
+        assert_eq!(records.len(), 8);
+
+        match records[0] {
+            DnsRecord::SOA {
+                ref domain,
+                ref m_name,
+                ref r_name,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ttl,
+            } => {
+                assert_eq!(domain, "example.com");
+                assert_eq!(m_name, "ns1.example.com");
+                assert_eq!(r_name, "hostmaster.example.com");
+                assert_eq!(serial, 2024010101);
+                assert_eq!(
+                    (refresh, retry, expire, minimum),
+                    (7200, 1800, 1209600, 300)
+                );
+                assert_eq!(ttl, 3600);
+            }
+            ref r => panic!("unexpected record {:?}", r),
+        }
+
+        assert!(
+            matches!(records[2], DnsRecord::NS { ref domain, ref host, .. }
+            if domain == "example.com" && host == "ns2.example.net")
+        );
+        assert!(
+            matches!(records[3], DnsRecord::MX { priority: 10, ref host, .. }
+            if host == "mail.example.com")
+        );
+        assert!(
+            matches!(records[4], DnsRecord::A { ref domain, addr, ttl: 3600 }
+            if domain == "ns1.example.com" && addr == Ipv4Addr::new(192, 0, 2, 1))
+        );
+        assert!(
+            matches!(records[5], DnsRecord::AAAA { ref domain, addr, .. }
+            if domain == "ns1.example.com" && addr == "2001:db8::1".parse::<Ipv6Addr>().unwrap())
+        );
+        assert!(
+            matches!(records[6], DnsRecord::CNAME { ref host, ttl: 600, .. }
+            if host == "example.com")
+        );
+        match records[7] {
+            DnsRecord::TXT { ref data, ttl, .. } => {
+                assert_eq!(data, &[&b"hello \"world\""[..], b"semi;colon", b"AB"]);
+                assert_eq!(ttl, 3600);
+            }
+            ref r => panic!("unexpected record {:?}", r),
+        }
+    }
+
+    #[test]
+    fn inherit_last_ttl_without_directive() {
+        let records = ZoneParser::new()
+            .origin("example.org.")
+            .parse_str("a 120 A 192.0.2.1\nb A 192.0.2.2\n")
+            .unwrap();
+        assert!(
+            matches!(records[1], DnsRecord::A { ttl: 120, ref domain, .. }
+            if domain == "b.example.org")
+        );
+    }
+
+    #[test]
+    fn include() {
+        let dir = std::env::temp_dir().join(format!("dns-zone-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("hosts.inc"), "host A 192.0.2.10\n").unwrap();
+        std::fs::write(
+            dir.join("main.zone"),
+            "$TTL 60\n$ORIGIN example.com.\n$INCLUDE hosts.inc sub\nafter A 192.0.2.11\n",
+        )
+        .unwrap();
+
+        let records = ZoneParser::new().parse_file(dir.join("main.zone")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(matches!(records[0], DnsRecord::A { ref domain, .. }
+            if domain == "host.sub.example.com"));
+        assert!(matches!(records[1], DnsRecord::A { ref domain, .. }
+            if domain == "after.example.com"));
+    }
+
+    #[test]
+    fn error_position() {
+        let e: ZoneError = ZoneParser::new()
+            .origin("example.com")
+            .parse_str("$TTL 60\nwww  A  192.0.2.300\n")
+            .unwrap_err();
+        assert_eq!((e.line, e.column), (2, 9));
+
+        let e = ZoneParser::new()
+            .parse_str("$TTL 60\n@ SOA a b (1 2 3 4 5\n")
+            .unwrap_err();
+        assert_eq!((e.line, e.column), (2, 11));
+        assert!(e.message.contains("unbalanced"));
+
+        let e = ZoneParser::new()
+            .parse_str("www 60 A 192.0.2.1\n")
+            .unwrap_err();
+        assert_eq!((e.line, e.column), (1, 1));
+    }
+}